@@ -1,125 +1,249 @@
 use super::block_store::BlockStore;
 use super::bump_block::BumpBlock;
+use super::error::{AllocError, AllocErrorKind};
 use super::size_class::SizeClass;
 use std::alloc::Layout;
-use std::cell::Cell;
-use std::sync::Arc;
+use std::cell::{Cell, UnsafeCell};
+use std::sync::{Arc, Mutex};
+
+// The blocks an AllocHead is allocating into, only the head's own thread
+// ever touches them.
+struct HeadBlocks {
+    head: Option<BumpBlock>,
+    overflow: Option<BumpBlock>,
+    // blocks fetched ahead of time to become the head, next one last
+    stash: Vec<BumpBlock>,
+    // full heads waiting to be handed back to the store together
+    full: Vec<BumpBlock>,
+}
+
+impl HeadBlocks {
+    fn take(&mut self) -> Vec<BumpBlock> {
+        let mut blocks: Vec<BumpBlock> = self.head.take().into_iter().collect();
+
+        blocks.extend(self.overflow.take());
+        blocks.append(&mut self.stash);
+        blocks.append(&mut self.full);
+        blocks
+    }
+
+    fn addresses(&self) -> impl Iterator<Item = usize> + '_ {
+        self.head
+            .iter()
+            .chain(self.overflow.iter())
+            .chain(self.stash.iter())
+            .chain(self.full.iter())
+            .map(|block| block.as_ptr() as usize)
+    }
+
+    fn head_alloc(&mut self, layout: Layout) -> Option<*const u8> {
+        self.head.as_mut()?.inner_alloc(layout)
+    }
+
+    fn overflow_alloc(&mut self, layout: Layout) -> Option<*const u8> {
+        self.overflow.as_mut()?.inner_alloc(layout)
+    }
+}
 
 pub struct AllocHead {
-    head: Cell<Option<BumpBlock>>,
-    overflow: Cell<Option<BumpBlock>>,
+    blocks: UnsafeCell<HeadBlocks>,
+    // the refresh epoch the blocks were taken in, see BlockStore::refresh
+    epoch: Cell<usize>,
+    // where the blocks are, published on the slow path for the store to see
+    addresses: Arc<Mutex<Vec<usize>>>,
     block_store: Arc<BlockStore>,
+    stash_size: usize,
 }
 
 impl Drop for AllocHead {
+    // Stashed blocks that were never allocated into go back as free blocks,
+    // so they stop counting towards the arena size.
     fn drop(&mut self) {
-        if let Some(head) = self.head.take() {
-            self.block_store.push_recycle(head);
+        let blocks = self.blocks.get_mut();
+        let usable = blocks
+            .head
+            .take()
+            .into_iter()
+            .chain(blocks.overflow.take())
+            .chain(blocks.stash.drain(..));
+
+        for block in usable {
+            if block.is_free() {
+                self.block_store.push_free(block);
+            } else {
+                self.block_store.push_recycle(block);
+            }
         }
 
-        if let Some(overflow) = self.overflow.take() {
-            self.block_store.push_recycle(overflow);
-        }
+        self.block_store.push_rest_batch(blocks.full.drain(..));
     }
 }
 
 impl AllocHead {
     pub fn new(block_store: Arc<BlockStore>) -> Self {
+        let addresses = Arc::new(Mutex::new(vec![]));
+
+        block_store.register_head(&addresses);
+
         Self {
-            head: Cell::new(None),
-            overflow: Cell::new(None),
+            blocks: UnsafeCell::new(HeadBlocks {
+                head: None,
+                overflow: None,
+                stash: vec![],
+                full: vec![],
+            }),
+            epoch: Cell::new(block_store.get_epoch()),
+            addresses,
+            stash_size: block_store.get_head_stash_size(),
             block_store,
         }
     }
 
-    pub fn alloc(&self, layout: Layout) -> Result<*const u8, ()> {
-        if let Some(space) = self.head_alloc(layout) {
-            return Ok(space);
-        }
+    // init is run on the space before anything else can see it, a large
+    // object is only handed to the store once its header is written.
+    pub fn alloc<F>(&self, layout: Layout, init: F) -> Result<*const u8, AllocError>
+    where
+        F: FnOnce(*const u8),
+    {
+        // AllocHead isn't Sync and init only runs once the borrow is over, so
+        // this is the only reference to the blocks
+        let blocks = unsafe { &mut *self.blocks.get() };
+
+        let result = match blocks.head_alloc(layout) {
+            Some(space) => Ok(space),
+            None => match SizeClass::get_for_size(layout.size()) {
+                Ok(SizeClass::Small) => self.small_alloc(blocks, layout),
+                Ok(SizeClass::Medium) => self.medium_alloc(blocks, layout),
+                Ok(SizeClass::Large) => {
+                    return self
+                        .block_store
+                        .create_large(layout, init)
+                        .map_err(|kind| AllocError::new(kind, layout));
+                }
+                Err(kind) => Err(kind),
+            },
+        };
 
-        let size_class = SizeClass::get_for_size(layout.size())?;
-        match size_class {
-            SizeClass::Small => self.small_alloc(layout),
-            SizeClass::Medium => self.medium_alloc(layout),
-            SizeClass::Large => self.block_store.create_large(layout),
-        }
+        let space = result.map_err(|kind| AllocError::new(kind, layout))?;
+
+        init(space);
+        Ok(space)
     }
 
-    fn small_alloc(&self, layout: Layout) -> Result<*const u8, ()> {
+    fn small_alloc(
+        &self,
+        blocks: &mut HeadBlocks,
+        layout: Layout,
+    ) -> Result<*const u8, AllocErrorKind> {
+        self.sync_epoch(blocks);
+
         // this is okay be we already tried to alloc in head and didn't have space
         // and any block returned by get new head should have space for a small object
-        loop {
-            self.get_new_head()?;
+        let result = loop {
+            if let Err(kind) = self.get_new_head(blocks) {
+                break Err(kind);
+            }
 
-            if let Some(ptr) = self.head_alloc(layout) {
-                return Ok(ptr);
+            if let Some(ptr) = blocks.head_alloc(layout) {
+                break Ok(ptr);
             }
-        }
+        };
+
+        self.publish_addresses(blocks);
+        result
+    }
+
+    fn medium_alloc(
+        &self,
+        blocks: &mut HeadBlocks,
+        layout: Layout,
+    ) -> Result<*const u8, AllocErrorKind> {
+        self.sync_epoch(blocks);
+
+        let result = loop {
+            if let Some(space) = blocks.overflow_alloc(layout) {
+                break Ok(space);
+            }
+
+            if let Err(kind) = self.get_new_overflow(blocks) {
+                break Err(kind);
+            }
+        };
+
+        self.publish_addresses(blocks);
+        result
     }
 
-    fn medium_alloc(&self, layout: Layout) -> Result<*const u8, ()> {
-        loop {
-            if let Some(space) = self.overflow_alloc(layout) {
-                return Ok(space);
+    // The safepoint of the head, only reached on the slow path. Blocks held
+    // since before the last refresh weren't swept by it, so they go back to
+    // the store to be swept by the next one.
+    fn sync_epoch(&self, blocks: &mut HeadBlocks) {
+        let epoch = self.block_store.get_epoch();
+
+        if self.epoch.replace(epoch) != epoch {
+            let (free, used): (Vec<BumpBlock>, Vec<BumpBlock>) =
+                blocks.take().into_iter().partition(|block| block.is_free());
+
+            for block in free {
+                self.block_store.push_free(block);
             }
 
-            self.get_new_overflow()?;
+            self.block_store.push_rest_batch(used);
         }
     }
 
-    fn get_new_head(&self) -> Result<(), ()> {
-        let new_head = match self.overflow.take() {
+    fn publish_addresses(&self, blocks: &HeadBlocks) {
+        let mut addresses = self.addresses.lock().unwrap();
+
+        addresses.clear();
+        addresses.extend(blocks.addresses());
+    }
+
+    fn get_new_head(&self, blocks: &mut HeadBlocks) -> Result<(), AllocErrorKind> {
+        let new_head = match blocks.overflow.take() {
             Some(block) => block,
-            None => self.block_store.get_head()?,
+            None => self.take_stashed(blocks)?,
         };
 
-        let rest_block = self.head.take();
-        self.head.set(Some(new_head));
+        if let Some(block) = blocks.head.replace(new_head) {
+            blocks.full.push(block);
 
-        if let Some(block) = rest_block {
-            self.block_store.push_rest(block);
+            if blocks.full.len() >= self.stash_size {
+                self.block_store.push_rest_batch(blocks.full.drain(..));
+            }
         }
 
         Ok(())
     }
 
-    fn get_new_overflow(&self) -> Result<(), ()> {
-        let new_overflow = self.block_store.get_overflow()?;
-        let recycle_block = self.overflow.take();
-        self.overflow.set(Some(new_overflow));
+    fn take_stashed(&self, blocks: &mut HeadBlocks) -> Result<BumpBlock, AllocErrorKind> {
+        if self.stash_size == 0 {
+            return self.block_store.get_head();
+        }
 
-        if let Some(block) = recycle_block {
-            self.block_store.push_recycle(block);
+        if blocks.stash.is_empty() {
+            blocks.stash = self.block_store.get_heads(self.stash_size)?;
+            // recycled blocks come first in the batch, use them first
+            blocks.stash.reverse();
         }
 
-        Ok(())
+        Ok(blocks.stash.pop().unwrap())
     }
 
-    fn head_alloc(&self, layout: Layout) -> Option<*const u8> {
-        match self.head.take() {
-            Some(mut head) => {
-                let result = head.inner_alloc(layout);
-                self.head.set(Some(head));
-                result
-            }
-            None => None,
-        }
-    }
+    fn get_new_overflow(&self, blocks: &mut HeadBlocks) -> Result<(), AllocErrorKind> {
+        let new_overflow = self.block_store.get_overflow()?;
 
-    fn overflow_alloc(&self, layout: Layout) -> Option<*const u8> {
-        match self.overflow.take() {
-            Some(mut overflow) => {
-                let result = overflow.inner_alloc(layout);
-                self.overflow.set(Some(overflow));
-                result
-            }
-            None => None,
+        if let Some(block) = blocks.overflow.replace(new_overflow) {
+            self.block_store.push_recycle(block);
         }
+
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::super::arena_config::ArenaConfig;
     use super::super::constants;
     use super::*;
 
@@ -131,29 +255,29 @@ mod tests {
             Layout::from_size_align(constants::BLOCK_CAPACITY - constants::LINE_SIZE, 8).unwrap();
         let small_layout = Layout::from_size_align(constants::LINE_SIZE, 8).unwrap();
 
-        blocks.alloc(medium_layout).unwrap();
+        blocks.alloc(medium_layout, |_| {}).unwrap();
         assert_eq!(store.block_count(), 1);
 
-        blocks.alloc(medium_layout).unwrap();
+        blocks.alloc(medium_layout, |_| {}).unwrap();
         assert_eq!(store.block_count(), 2);
 
-        blocks.alloc(medium_layout).unwrap();
+        blocks.alloc(medium_layout, |_| {}).unwrap();
         assert_eq!(store.block_count(), 3);
 
         // this alloc should alloc should fill the head
-        blocks.alloc(small_layout).unwrap();
+        blocks.alloc(small_layout, |_| {}).unwrap();
         assert_eq!(store.block_count(), 3);
 
         // this alloc should alloc should fill the overflow head
-        blocks.alloc(small_layout).unwrap();
+        blocks.alloc(small_layout, |_| {}).unwrap();
         assert_eq!(store.block_count(), 3);
 
         // this alloc should alloc should fill the recycle
-        blocks.alloc(small_layout).unwrap();
+        blocks.alloc(small_layout, |_| {}).unwrap();
         assert_eq!(store.block_count(), 3);
 
         // this alloc should alloc should need a new block
-        blocks.alloc(small_layout).unwrap();
+        blocks.alloc(small_layout, |_| {}).unwrap();
         assert_eq!(store.block_count(), 4);
     }
 
@@ -164,7 +288,7 @@ mod tests {
         let medium_layout = Layout::from_size_align(constants::BLOCK_CAPACITY, 8).unwrap();
 
         for i in 1..100 {
-            blocks.alloc(medium_layout).unwrap();
+            blocks.alloc(medium_layout, |_| {}).unwrap();
             assert_eq!(store.block_count(), i);
         }
     }
@@ -176,13 +300,13 @@ mod tests {
         let medium_layout = Layout::from_size_align(constants::BLOCK_CAPACITY, 8).unwrap();
         let medium_layout_2 = Layout::from_size_align(constants::BLOCK_CAPACITY / 2, 8).unwrap();
 
-        blocks.alloc(medium_layout).unwrap();
-        blocks.alloc(medium_layout_2).unwrap();
-        blocks.alloc(medium_layout_2).unwrap();
+        blocks.alloc(medium_layout, |_| {}).unwrap();
+        blocks.alloc(medium_layout_2, |_| {}).unwrap();
+        blocks.alloc(medium_layout_2, |_| {}).unwrap();
         assert_eq!(store.block_count(), 2);
 
-        blocks.alloc(medium_layout_2).unwrap();
-        blocks.alloc(medium_layout_2).unwrap();
+        blocks.alloc(medium_layout_2, |_| {}).unwrap();
+        blocks.alloc(medium_layout_2, |_| {}).unwrap();
         assert_eq!(store.block_count(), 3);
     }
 
@@ -196,10 +320,10 @@ mod tests {
         let mut med_ptrs = Vec::<*const u8>::new();
 
         for _ in 0..2000 {
-            let ptr = blocks.alloc(small_layout).unwrap();
+            let ptr = blocks.alloc(small_layout, |_| {}).unwrap();
             small_ptrs.push(ptr);
 
-            let med_ptr = blocks.alloc(medium_layout).unwrap();
+            let med_ptr = blocks.alloc(medium_layout, |_| {}).unwrap();
             med_ptrs.push(med_ptr);
         }
 
@@ -210,4 +334,50 @@ mod tests {
             assert!(!small_ptrs.contains(&ptr));
         }
     }
+
+    #[test]
+    fn stash_blocks_in_batches() {
+        let config = ArenaConfig::new().head_stash_size(4).initial_blocks(8);
+        let store = Arc::new(BlockStore::with_config(config));
+        let blocks = AllocHead::new(store.clone());
+        let small_layout = Layout::from_size_align(constants::LINE_SIZE, 8).unwrap();
+
+        blocks.alloc(small_layout, |_| {}).unwrap();
+        assert_eq!(store.block_count(), 4);
+
+        // the whole stash is used up before the next batch is fetched
+        let mut allocs = 0;
+        while store.block_count() == 4 {
+            blocks.alloc(small_layout, |_| {}).unwrap();
+            allocs += 1;
+        }
+
+        assert_eq!(store.block_count(), 8);
+        assert!(allocs > constants::BLOCK_CAPACITY / (constants::LINE_SIZE * 2) * 3);
+    }
+
+    #[test]
+    fn unused_stash_goes_back_free() {
+        let config = ArenaConfig::new().head_stash_size(4).initial_blocks(8);
+        let store = Arc::new(BlockStore::with_config(config));
+        let blocks = AllocHead::new(store.clone());
+
+        blocks.alloc(Layout::new::<u64>(), |_| {}).unwrap();
+        assert_eq!(store.block_count(), 4);
+
+        // only the head was allocated into
+        drop(blocks);
+        assert_eq!(store.block_count(), 1);
+    }
+
+    #[test]
+    fn stash_never_makes_blocks_ahead() {
+        let store = Arc::new(BlockStore::with_config(
+            ArenaConfig::new().head_stash_size(4),
+        ));
+        let blocks = AllocHead::new(store.clone());
+
+        blocks.alloc(Layout::new::<u64>(), |_| {}).unwrap();
+        assert_eq!(store.block_count(), 1);
+    }
 }
@@ -1,3 +1,4 @@
+use super::error::AllocError;
 use std::alloc::Layout;
 use std::fmt::Debug;
 use std::ptr::NonNull;
@@ -6,18 +7,42 @@ pub trait Allocate: 'static {
     type Arena: GenerationalArena;
 
     fn new(arena: &Self::Arena) -> Self;
-    fn alloc(&self, layout: Layout) -> Result<NonNull<u8>, ()>;
+    fn alloc(&self, layout: Layout) -> Result<NonNull<u8>, AllocError>;
+
+    // The finalizer is called with the object once a refresh finds it unmarked.
+    // Finalizers run during the refresh, before any memory is reclaimed, in the
+    // order their objects were allocated. They may allocate in the arena but
+    // must not refresh it, and should not touch other unmarked objects which
+    // may already be finalized.
+    fn alloc_with_finalizer(
+        &self,
+        layout: Layout,
+        finalizer: fn(*mut u8),
+    ) -> Result<NonNull<u8>, AllocError>;
     fn get_mark<T>(ptr: NonNull<T>) -> <<Self as Allocate>::Arena as GenerationalArena>::Mark;
     fn set_mark<T>(ptr: NonNull<T>, mark: <<Self as Allocate>::Arena as GenerationalArena>::Mark);
 
+    fn get_forward<T>(ptr: NonNull<T>) -> Option<NonNull<T>>;
+    // Fails with where the object stays, the copy of whichever evacuation got
+    // there first or the object itself if it is pinned.
+    fn try_forward<T>(ptr: NonNull<T>, new_ptr: NonNull<T>) -> Result<(), NonNull<T>>;
+
+    // Moves the object out of a block selected by GenerationalArena::prepare_evacuation,
+    // leaving a forwarding pointer behind. Returns where the object now lives,
+    // which is the new location that should be marked.
+    fn evacuate<T>(&self, ptr: NonNull<T>) -> NonNull<T>;
+
     fn is_old<T>(&self, ptr: NonNull<T>) -> bool;
 }
 
 pub trait GenerationalArena {
     type Mark: Marker;
+    type Config;
 
     fn new() -> Self;
+    fn new_with_config(config: Self::Config) -> Self;
     fn refresh(&self);
+    fn prepare_evacuation(&self) -> usize;
     fn get_size(&self) -> usize;
     fn current_mark(&self) -> Self::Mark;
     fn rotate_mark(&self) -> Self::Mark;
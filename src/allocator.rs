@@ -1,53 +1,49 @@
 use super::alloc_head::AllocHead;
-use super::allocate::Allocate;
+use super::allocate::{Allocate, GenerationalArena, Marker};
 use super::arena::Arena;
+use super::block_meta::BlockMeta;
+use super::constants::MAX_ALLOC_SIZE;
+use super::error::{AllocError, AllocErrorKind};
 use super::header::Header;
 use super::header::Mark;
+use super::satb::SatbBuffer;
 use super::size_class::SizeClass;
 use std::alloc::Layout;
 use std::mem::{align_of, size_of};
-use std::ptr::write;
 use std::ptr::NonNull;
-use std::sync::atomic::{AtomicU8, Ordering};
-use std::sync::Arc;
+use std::ptr::{copy_nonoverlapping, write};
 
 pub struct Allocator {
     head: AllocHead,
-    current_mark: Arc<AtomicU8>,
+    satb: SatbBuffer,
+    arena: Arena,
 }
 
 impl Allocate for Allocator {
     type Arena = Arena;
 
     fn new(arena: &Self::Arena) -> Self {
-        let current_mark = arena.get_current_mark_ref();
-
         Self {
             head: AllocHead::new(arena.get_block_store()),
-            current_mark,
+            satb: SatbBuffer::new(arena.get_block_store()),
+            arena: arena.clone(),
         }
     }
 
-    fn alloc(&self, layout: Layout) -> Result<NonNull<u8>, ()> {
-        let align = std::cmp::max(align_of::<Header>(), layout.align());
-        let header_size = size_of::<Header>();
-        let padding = (align - (header_size % align)) % align;
-        let alloc_size = header_size + padding + layout.size();
-        let size_class = SizeClass::get_for_size(alloc_size)?;
-        // Alloc size could be greater than u16, causing overflow conversion from (as u16).
-        // This is okay though, b/c in that case the object will be SizeClass::Large
-        // where the header size is unused. Normally the header size is used,
-        // for marking block lines, but large objects are stored in bump blocks.
-        let header = Header::new(size_class, alloc_size as u16);
+    fn alloc(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        self.alloc_init(layout, |_| {})
+    }
 
-        unsafe {
-            let alloc_layout = Layout::from_size_align(alloc_size, align).unwrap();
-            let space = self.head.alloc(alloc_layout)?;
-            let object_space = space.add(header_size + padding);
+    fn alloc_with_finalizer(
+        &self,
+        layout: Layout,
+        finalizer: fn(*mut u8),
+    ) -> Result<NonNull<u8>, AllocError> {
+        let block_store = self.arena.get_block_store_ref();
 
-            write(space as *mut Header, header);
-            Ok(NonNull::new(object_space as *mut u8).unwrap())
-        }
+        self.alloc_init(layout, |header| {
+            block_store.push_finalizer(header, finalizer)
+        })
     }
 
     fn get_mark<T>(ptr: NonNull<T>) -> Mark {
@@ -62,35 +58,220 @@ impl Allocate for Allocator {
         Header::set_mark(header_ptr, mark)
     }
 
+    fn evacuate<T>(&self, ptr: NonNull<T>) -> NonNull<T> {
+        let header = Self::get_header(ptr);
+
+        if let Some(forward) = Self::get_forward(ptr) {
+            return forward;
+        }
+
+        if !Self::is_evacuating(header) || unsafe { (*header).is_pinned() } {
+            return ptr;
+        }
+
+        let align = std::cmp::max(align_of::<Header>(), align_of::<T>());
+        let alloc_size = unsafe { (*header).get_size() } as usize;
+        let object_offset = ptr.as_ptr() as usize - header as usize;
+        let alloc_layout = Layout::from_size_align(alloc_size, align).unwrap();
+
+        // if there is no space to evacuate into the object just stays put
+        let copy = |space: *const u8| unsafe {
+            copy_nonoverlapping(header as *const u8, space as *mut u8, alloc_size);
+            Header::clear_copy(space as *const Header);
+        };
+        let Ok(space) = self.head.alloc(alloc_layout, copy) else {
+            return ptr;
+        };
+
+        unsafe {
+            let new_ptr = NonNull::new(space.add(object_offset) as *mut T).unwrap();
+
+            // a tracer on another thread may have evacuated the object first,
+            // or it was pinned since it was checked above, in which case our
+            // copy is left unmarked to be swept
+            match Self::try_forward(ptr, new_ptr) {
+                Ok(()) => new_ptr,
+                Err(forward) => forward,
+            }
+        }
+    }
+
+    fn get_forward<T>(ptr: NonNull<T>) -> Option<NonNull<T>> {
+        let header_ptr = Self::get_header(ptr);
+
+        Header::get_forward(header_ptr).map(|forward| NonNull::new(forward as *mut T).unwrap())
+    }
+
+    fn try_forward<T>(ptr: NonNull<T>, new_ptr: NonNull<T>) -> Result<(), NonNull<T>> {
+        let header_ptr = Self::get_header(ptr);
+
+        Header::try_forward(header_ptr, new_ptr.as_ptr() as *const u8).map_err(|forward| {
+            match forward {
+                Some(forward) => NonNull::new(forward as *mut T).unwrap(),
+                None => ptr,
+            }
+        })
+    }
+
     fn is_old<T>(&self, ptr: NonNull<T>) -> bool {
         Self::get_mark(ptr) == self.get_current_mark()
     }
 }
 
 impl Allocator {
+    // init is called with the header while the space can't be swept yet.
+    fn alloc_init<F>(&self, layout: Layout, init: F) -> Result<NonNull<u8>, AllocError>
+    where
+        F: FnOnce(*const Header),
+    {
+        let error = |kind| AllocError::new(kind, layout);
+
+        if layout.size() == 0 {
+            return Err(error(AllocErrorKind::ZeroSize));
+        }
+
+        self.arena.run_soft_limit_callback();
+
+        let align = std::cmp::max(align_of::<Header>(), layout.align());
+        let header_size = size_of::<Header>();
+        let padding = (align - (header_size % align)) % align;
+        let alloc_size = (header_size + padding)
+            .checked_add(layout.size())
+            .filter(|size| *size <= MAX_ALLOC_SIZE)
+            .ok_or(error(AllocErrorKind::TooLarge))?;
+        let alloc_layout = Layout::from_size_align(alloc_size, align)
+            .map_err(|_| error(AllocErrorKind::InvalidLayout))?;
+        let size_class = SizeClass::get_for_size(alloc_size).map_err(error)?;
+        // alloc_size is at most MAX_ALLOC_SIZE, so it always fits in the header
+        let header = Header::new(size_class, alloc_size as u32, align);
+
+        // the header is written before a refresh can see the space, see AllocHead::alloc
+        let space = self
+            .head
+            .alloc(alloc_layout, |space| unsafe {
+                let header_ptr = space as *const Header;
+
+                write(header_ptr as *mut Header, header);
+
+                // allocate black, objects made while marking are already live
+                if self.arena.is_marking() {
+                    Header::set_mark(header_ptr, self.get_current_mark());
+                }
+
+                init(header_ptr);
+            })
+            .map_err(|err| error(err.kind()))?;
+        let object_space = unsafe { space.add(header_size + padding) };
+
+        Ok(NonNull::new(object_space as *mut u8).unwrap())
+    }
+
     pub fn get_header<T>(object: NonNull<T>) -> *const Header {
         let align = std::cmp::max(align_of::<Header>(), align_of::<T>());
         let header_size = size_of::<Header>();
         let padding = (align - (header_size % align)) % align;
         let ptr: *mut u8 = object.as_ptr().cast::<u8>();
 
-        debug_assert!((ptr as usize % align) == 0);
-        debug_assert!((object.as_ptr() as usize % align_of::<T>()) == 0);
+        debug_assert!((ptr as usize).is_multiple_of(align));
+        debug_assert!((object.as_ptr() as usize).is_multiple_of(align_of::<T>()));
 
         unsafe { ptr.sub(header_size + padding) as *const Header }
     }
 
+    // Call after storing a pointer to target inside of src. An old object that
+    // now points at a New one is remembered, see Arena::dirty_cards.
+    pub fn write_barrier<T, U>(&self, src: NonNull<T>, target: NonNull<U>) {
+        if Self::get_mark(src).is_new() || !Self::get_mark(target).is_new() {
+            return;
+        }
+
+        self.arena
+            .get_block_store_ref()
+            .write_barrier(Self::get_header(src));
+    }
+
+    // Marks the object unless it already has the mark, returning whether this
+    // call marked it. Only one of several threads racing to mark it wins.
+    pub fn try_mark<T>(ptr: NonNull<T>, mark: Mark) -> bool {
+        Header::try_mark(Self::get_header(ptr), mark)
+    }
+
+    // Snapshot at the beginning barrier, call with the old target before a
+    // reference to it is overwritten. While the arena is marking, targets that
+    // aren't marked yet are logged for the marker, see Arena::drain_satb.
+    pub fn satb_write_barrier<T>(&self, old_target: NonNull<T>) {
+        if !self.arena.is_marking() || Self::get_mark(old_target) == self.get_current_mark() {
+            return;
+        }
+
+        self.satb.push(old_target.as_ptr() as *const u8);
+    }
+
+    // Hands the logged references over to the arena right away.
+    pub fn flush_satb(&self) {
+        self.satb.flush();
+    }
+
+    // A pinned object is never evacuated, so its address stays stable until
+    // it is unpinned. Large objects never move and are always pinned, unpinning
+    // them does nothing. An object an evacuation already moved can't be pinned
+    // where it was, its copy is pinned instead and returned.
+    pub fn pin<T>(ptr: NonNull<T>) -> NonNull<T> {
+        let mut ptr = ptr;
+
+        loop {
+            let header = Self::get_header(ptr);
+
+            match Header::pin(header) {
+                Ok(pinned) => {
+                    if !pinned && unsafe { (*header).get_size_class() } != SizeClass::Large {
+                        BlockMeta::from_header(header).pin();
+                    }
+
+                    return ptr;
+                }
+                Err(forward) => ptr = NonNull::new(forward as *mut T).unwrap(),
+            }
+        }
+    }
+
+    pub fn unpin<T>(ptr: NonNull<T>) {
+        let header = Self::get_header(ptr);
+
+        if unsafe { (*header).get_size_class() } == SizeClass::Large {
+            return;
+        }
+
+        if Header::unpin(header) {
+            BlockMeta::from_header(header).unpin();
+        }
+    }
+
+    pub fn is_pinned<T>(ptr: NonNull<T>) -> bool {
+        unsafe { (*Self::get_header(ptr)).is_pinned() }
+    }
+
     fn get_current_mark(&self) -> Mark {
-        Mark::from(self.current_mark.load(Ordering::SeqCst))
+        self.arena.current_mark()
+    }
+
+    fn is_evacuating(header: *const Header) -> bool {
+        let size_class = unsafe { (*header).get_size_class() };
+
+        size_class != SizeClass::Large && BlockMeta::from_header(header).is_evacuating()
     }
 }
 #[cfg(test)]
 mod tests {
-    use super::*; 
-    use crate::constants::{BLOCK_SIZE, BLOCK_CAPACITY};
-    use crate::arena::Arena;
+    use super::*;
     use crate::allocate::{Allocate, GenerationalArena};
+    use crate::arena::Arena;
+    use crate::arena_config::ArenaConfig;
+    use crate::constants::{BLOCK_CAPACITY, BLOCK_SIZE};
     use crate::header::{Header, Mark};
+    use std::cell::RefCell;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
 
     #[test]
     fn hello_alloc() {
@@ -138,6 +319,23 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn alloc_errors() {
+        let arena = Arena::new();
+        let allocator = Allocator::new(&arena);
+        let zero = Layout::from_size_align(0, 8).unwrap();
+        let too_big = Layout::from_size_align(MAX_ALLOC_SIZE, 8).unwrap();
+
+        let err = allocator.alloc(zero).unwrap_err();
+        assert_eq!(err.kind(), AllocErrorKind::ZeroSize);
+        assert_eq!(err.layout(), zero);
+
+        let err = allocator.alloc(too_big).unwrap_err();
+        assert_eq!(err.kind(), AllocErrorKind::TooLarge);
+        assert_eq!(err.layout(), too_big);
+        assert!(err.to_string().contains(&MAX_ALLOC_SIZE.to_string()));
+    }
+
     #[test]
     fn alloc_two_large_arrays() {
         let arena = Arena::new();
@@ -227,4 +425,785 @@ mod tests {
 
         assert_eq!(arena.get_size(), (BLOCK_SIZE + large_size));
     }
+
+    #[test]
+    fn evacuate_sparse_blocks() {
+        let arena = Arena::new();
+        let allocator = Allocator::new(&arena);
+        let layout = Layout::new::<[u64; 8]>();
+        let mut survivors: Vec<NonNull<[u64; 8]>> = vec![];
+
+        for i in 0..10_000 {
+            let ptr: NonNull<[u64; 8]> = allocator.alloc(layout).unwrap().cast();
+
+            unsafe { ptr.as_ptr().write([i; 8]) };
+
+            if i % 1_000 == 0 {
+                Allocator::set_mark(ptr, arena.current_mark());
+                survivors.push(ptr);
+            }
+        }
+
+        arena.refresh();
+        assert!(arena.prepare_evacuation() > 0);
+
+        let mark = arena.rotate_mark();
+        let evacuated: Vec<NonNull<[u64; 8]>> = survivors
+            .iter()
+            .map(|ptr| {
+                let new_ptr = allocator.evacuate(*ptr);
+
+                Allocator::set_mark(new_ptr, mark);
+                new_ptr
+            })
+            .collect();
+
+        assert!(survivors != evacuated);
+
+        for (old, new) in survivors.iter().zip(evacuated.iter()) {
+            let header = Allocator::get_header(*old);
+
+            if old != new {
+                assert!(Header::is_forwarded(header));
+                assert_eq!(Allocator::get_forward(*old), Some(*new));
+                assert_eq!(allocator.evacuate(*old), *new);
+            }
+
+            unsafe { assert_eq!(old.as_ref(), new.as_ref()) };
+        }
+
+        let size = arena.get_size();
+        arena.refresh();
+        assert!(arena.get_size() < size);
+    }
+
+    #[test]
+    fn soft_limit_callback() {
+        let arena = Arena::new();
+        let allocator = Allocator::new(&arena);
+        let layout = Layout::from_size_align(BLOCK_CAPACITY / 2, 8).unwrap();
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+
+        arena.set_soft_limit(BLOCK_SIZE * 4, move |arena| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            arena.refresh();
+        });
+
+        for _ in 0..4 {
+            allocator.alloc(layout).unwrap();
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        // the fifth block crosses the limit, the callback runs on the next alloc
+        allocator.alloc(layout).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+        assert_eq!(arena.get_size(), BLOCK_SIZE * 5);
+
+        allocator.alloc(layout).unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert!(arena.get_size() < BLOCK_SIZE * 4);
+    }
+
+    #[test]
+    fn soft_limit_rearms_after_refresh() {
+        let arena = Arena::new();
+        let allocator = Allocator::new(&arena);
+        let layout = Layout::from_size_align(BLOCK_CAPACITY / 2, 8).unwrap();
+        let alloc_live = || {
+            let ptr = allocator.alloc(layout).unwrap();
+
+            Allocator::set_mark(ptr, arena.current_mark());
+        };
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+
+        // counts without refreshing, so only refreshes from the test re-arm
+        arena.set_soft_limit(BLOCK_SIZE * 4, move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        });
+
+        for _ in 0..8 {
+            alloc_live();
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // everything survives, so the arena is still over the limit
+        arena.refresh();
+        assert!(arena.get_size() > BLOCK_SIZE * 4);
+
+        // growing hits the limit again, the callback runs on the next alloc
+        alloc_live();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        alloc_live();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn hard_limit() {
+        let arena = Arena::new();
+        let allocator = Allocator::new(&arena);
+        let medium = Layout::from_size_align(BLOCK_CAPACITY / 2, 8).unwrap();
+        let large = Layout::from_size_align(BLOCK_SIZE * 2, 8).unwrap();
+
+        arena.set_hard_limit(BLOCK_SIZE * 2);
+
+        allocator.alloc(medium).unwrap();
+        allocator.alloc(medium).unwrap();
+
+        let err = allocator.alloc(medium).unwrap_err();
+        assert_eq!(err.kind(), AllocErrorKind::HeapLimit);
+        assert_eq!(err.layout(), medium);
+
+        let err = allocator.alloc(large).unwrap_err();
+        assert_eq!(err.kind(), AllocErrorKind::HeapLimit);
+    }
+
+    #[test]
+    fn refresh_with_idle_allocators() {
+        let arena = Arena::new();
+        let layout = Layout::new::<[u8; 64]>();
+        let parked = Arc::new(std::sync::Barrier::new(5));
+        let refreshed = Arc::new(std::sync::Barrier::new(5));
+
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let arena = arena.clone();
+                let parked = parked.clone();
+                let refreshed = refreshed.clone();
+
+                std::thread::spawn(move || {
+                    let allocator = Allocator::new(&arena);
+
+                    for _ in 0..1_000 {
+                        allocator.alloc(layout).unwrap();
+                    }
+
+                    parked.wait();
+                    refreshed.wait();
+
+                    for _ in 0..1_000 {
+                        allocator.alloc(layout).unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        parked.wait();
+        assert!(arena.get_size() > 4 * BLOCK_SIZE);
+        arena.refresh();
+        // an idle allocator keeps its head block until its next slow path
+        assert_eq!(arena.get_size(), 4 * BLOCK_SIZE);
+        refreshed.wait();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        arena.refresh();
+        assert_eq!(arena.get_size(), 0);
+    }
+
+    #[test]
+    fn refresh_while_allocating() {
+        let arena = Arena::new();
+        let layout = Layout::new::<[u8; 64]>();
+
+        // objects are allocated black, so every refresh has to keep them
+        arena.start_marking();
+
+        let objects: Vec<(u8, usize)> = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..4u8)
+                .map(|t| {
+                    let arena = &arena;
+
+                    scope.spawn(move || {
+                        let allocator = Allocator::new(arena);
+                        let mut objects = vec![];
+
+                        for _ in 0..20_000 {
+                            let ptr = allocator.alloc(layout).unwrap();
+
+                            unsafe { ptr.as_ptr().write_bytes(t, 64) };
+                            objects.push((t, ptr.as_ptr() as usize));
+                        }
+
+                        objects
+                    })
+                })
+                .collect();
+
+            for _ in 0..20 {
+                arena.refresh();
+            }
+
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect()
+        });
+
+        arena.stop_marking();
+        arena.refresh();
+
+        for (t, addr) in objects {
+            let ptr = NonNull::new(addr as *mut [u8; 64]).unwrap();
+
+            assert_eq!(Allocator::get_mark(ptr), arena.current_mark());
+            assert!(unsafe { ptr.as_ref() }.iter().all(|byte| *byte == t));
+        }
+    }
+
+    #[test]
+    fn lazy_refresh_keeps_marked_objects() {
+        let arena = Arena::new_with_config(ArenaConfig::new().lazy_sweep(true));
+        let allocator = Allocator::new(&arena);
+        let layout = Layout::new::<[u64; 8]>();
+        let mut survivors: Vec<NonNull<[u64; 8]>> = vec![];
+
+        for i in 0..5_000 {
+            let ptr: NonNull<[u64; 8]> = allocator.alloc(layout).unwrap().cast();
+
+            unsafe { ptr.as_ptr().write([i; 8]) };
+
+            if i % 10 == 0 {
+                Allocator::set_mark(ptr, arena.current_mark());
+                survivors.push(ptr);
+            }
+        }
+
+        let size = arena.get_size();
+        arena.refresh();
+        assert_eq!(arena.get_size(), size);
+
+        for _ in 0..5_000 {
+            let ptr: NonNull<[u64; 8]> = allocator.alloc(layout).unwrap().cast();
+
+            unsafe { ptr.as_ptr().write([u64::MAX; 8]) };
+        }
+
+        // the swept blocks were reused instead of growing the heap
+        assert!(arena.get_size() < size * 2);
+
+        for (i, ptr) in survivors.iter().enumerate() {
+            unsafe { assert_eq!(*ptr.as_ref(), [i as u64 * 10; 8]) };
+        }
+    }
+
+    #[test]
+    fn lazy_refresh_shrinks_heap() {
+        let arena = Arena::new_with_config(ArenaConfig::new().lazy_sweep(true));
+        let allocator = Allocator::new(&arena);
+        let layout = Layout::new::<[u64; 8]>();
+
+        for _ in 0..5_000 {
+            allocator.alloc(layout).unwrap();
+        }
+
+        assert!(arena.get_size() > 0);
+        drop(allocator);
+        arena.refresh();
+
+        // nothing survived, so every block and region is given back
+        assert_eq!(arena.get_size(), 0);
+        assert_eq!(arena.get_block_store().region_count(), 0);
+    }
+
+    #[test]
+    fn forward_only_once() {
+        let arena = Arena::new();
+        let allocator = Allocator::new(&arena);
+        let layout = Layout::new::<u64>();
+        let ptr: NonNull<u64> = allocator.alloc(layout).unwrap().cast();
+        let first: NonNull<u64> = allocator.alloc(layout).unwrap().cast();
+        let second: NonNull<u64> = allocator.alloc(layout).unwrap().cast();
+
+        assert_eq!(Allocator::get_forward(ptr), None);
+        assert_eq!(Allocator::try_forward(ptr, first), Ok(()));
+        assert_eq!(Allocator::try_forward(ptr, second), Err(first));
+        assert_eq!(Allocator::get_forward(ptr), Some(first));
+    }
+
+    #[test]
+    fn pinned_or_forwarded_never_both() {
+        let arena = Arena::new();
+        let allocator = Allocator::new(&arena);
+        let layout = Layout::new::<u64>();
+        let pinned: NonNull<u64> = allocator.alloc(layout).unwrap().cast();
+        let moved: NonNull<u64> = allocator.alloc(layout).unwrap().cast();
+        let copy: NonNull<u64> = allocator.alloc(layout).unwrap().cast();
+
+        assert_eq!(Allocator::pin(pinned), pinned);
+        assert_eq!(Allocator::try_forward(pinned, copy), Err(pinned));
+        assert_eq!(Allocator::get_forward(pinned), None);
+
+        assert_eq!(Allocator::try_forward(moved, copy), Ok(()));
+        assert_eq!(Allocator::pin(moved), copy);
+        assert!(!Allocator::is_pinned(moved));
+        assert!(Allocator::is_pinned(copy));
+    }
+
+    #[test]
+    fn concurrent_forward() {
+        let arena = Arena::new();
+        let allocator = Allocator::new(&arena);
+        let layout = Layout::new::<u64>();
+        let ptr = allocator.alloc(layout).unwrap().as_ptr() as usize;
+        let targets: Vec<usize> = (0..8)
+            .map(|_| allocator.alloc(layout).unwrap().as_ptr() as usize)
+            .collect();
+
+        let winners: Vec<usize> = std::thread::scope(|scope| {
+            let handles: Vec<_> = targets
+                .iter()
+                .map(|target| {
+                    scope.spawn(move || {
+                        let ptr = NonNull::new(ptr as *mut u64).unwrap();
+                        let target = NonNull::new(*target as *mut u64).unwrap();
+
+                        match Allocator::try_forward(ptr, target) {
+                            Ok(()) => target.as_ptr() as usize,
+                            Err(winner) => winner.as_ptr() as usize,
+                        }
+                    })
+                })
+                .collect();
+
+            handles.into_iter().map(|h| h.join().unwrap()).collect()
+        });
+
+        assert!(winners.iter().all(|winner| *winner == winners[0]));
+        assert!(targets.contains(&winners[0]));
+    }
+
+    #[test]
+    fn find_interior_pointers() {
+        let arena = Arena::new();
+        let allocator = Allocator::new(&arena);
+        let layouts = [
+            Layout::from_size_align(24, 8).unwrap(),
+            Layout::from_size_align(1000, 64).unwrap(),
+            Layout::from_size_align(BLOCK_SIZE * 2, 128).unwrap(),
+        ];
+
+        for layout in layouts {
+            let ptr = allocator.alloc(layout).unwrap();
+
+            for offset in [0, layout.size() / 2, layout.size() - 1] {
+                let interior = unsafe { ptr.as_ptr().add(offset) };
+                let (start, header) = arena.find_object(interior).unwrap();
+
+                assert_eq!(start, ptr);
+                unsafe {
+                    assert_eq!((*header).get_align(), layout.align());
+                    assert!((*header).get_size() as usize > layout.size());
+                }
+            }
+
+            let past_end = unsafe { ptr.as_ptr().add(layout.size()) };
+            assert_ne!(arena.find_object(past_end).map(|found| found.0), Some(ptr));
+        }
+
+        assert!(arena.find_object(std::ptr::null::<u8>()).is_none());
+        assert!(arena.find_object(&arena as *const Arena).is_none());
+    }
+
+    #[test]
+    fn walk_every_object() {
+        let arena = Arena::new();
+        let allocator = Allocator::new(&arena);
+        let layouts = [
+            Layout::from_size_align(24, 8).unwrap(),
+            Layout::from_size_align(1000, 64).unwrap(),
+            Layout::from_size_align(BLOCK_SIZE * 2, 128).unwrap(),
+        ];
+        let mut live = vec![];
+        let mut all = vec![];
+
+        for i in 0..300 {
+            let ptr = allocator.alloc(layouts[i % 3]).unwrap();
+
+            if i % 2 == 0 {
+                live.push((ptr, i % 3));
+            }
+
+            all.push(ptr);
+        }
+
+        let mut found = vec![];
+        arena.for_each_object(|ptr, _| found.push(ptr));
+        found.sort();
+        all.sort();
+        assert_eq!(found, all);
+
+        for (ptr, _) in live.iter() {
+            let (_, header) = arena.find_object(ptr.as_ptr()).unwrap();
+
+            Header::set_mark(header, Mark::Red);
+        }
+
+        arena.refresh();
+
+        // dead objects sharing a line with a live one are still walked
+        let mut walked = 0;
+        let mut classes = [0; 3];
+        arena.for_each_object(|ptr, header| {
+            assert!(all.contains(&ptr));
+            walked += 1;
+
+            if Header::get_mark(header) != Mark::Red {
+                return;
+            }
+
+            assert!(live.iter().any(|(live, _)| *live == ptr));
+
+            match header.get_size_class() {
+                SizeClass::Small => classes[0] += 1,
+                SizeClass::Medium => classes[1] += 1,
+                SizeClass::Large => classes[2] += 1,
+            }
+        });
+
+        assert_eq!(classes, [50, 50, 50]);
+        assert!(walked < all.len());
+    }
+
+    static FINALIZED: Mutex<Vec<usize>> = Mutex::new(vec![]);
+
+    fn record_finalized(object: *mut u8) {
+        FINALIZED.lock().unwrap().push(object as usize);
+    }
+
+    #[test]
+    fn finalize_unmarked_objects() {
+        let arena = Arena::new();
+        let allocator = Allocator::new(&arena);
+        let layout = Layout::new::<[u64; 8]>();
+        let mut all = vec![];
+        let mut survivors = vec![];
+
+        for i in 0..1_000 {
+            let ptr = allocator
+                .alloc_with_finalizer(layout, record_finalized)
+                .unwrap();
+
+            if i % 100 == 0 {
+                Allocator::set_mark(ptr, arena.current_mark());
+                survivors.push(ptr);
+            } else {
+                all.push(ptr.as_ptr() as usize);
+            }
+        }
+
+        arena.refresh();
+        assert_eq!(*FINALIZED.lock().unwrap(), all);
+
+        // survivors that get evacuated keep their finalizer
+        arena.prepare_evacuation();
+        let mark = arena.rotate_mark();
+        for ptr in survivors.iter() {
+            let new_ptr = allocator.evacuate(*ptr);
+
+            Allocator::set_mark(new_ptr, mark);
+            all.push(new_ptr.as_ptr() as usize);
+        }
+
+        arena.refresh();
+        assert_eq!(FINALIZED.lock().unwrap().len(), 990);
+
+        arena.rotate_mark();
+        arena.refresh();
+        assert_eq!(*FINALIZED.lock().unwrap(), all);
+    }
+
+    thread_local! {
+        static FINALIZER_ALLOCATOR: RefCell<Option<Allocator>> = const { RefCell::new(None) };
+    }
+
+    fn alloc_in_finalizer(_: *mut u8) {
+        FINALIZER_ALLOCATOR.with_borrow(|allocator| {
+            let allocator = allocator.as_ref().unwrap();
+
+            allocator.alloc(Layout::new::<[u64; 8]>()).unwrap();
+        });
+    }
+
+    #[test]
+    fn finalizers_can_allocate() {
+        let arena = Arena::new();
+        let layout = Layout::new::<[u64; 8]>();
+
+        FINALIZER_ALLOCATOR.set(Some(Allocator::new(&arena)));
+        FINALIZER_ALLOCATOR.with_borrow(|allocator| {
+            for _ in 0..1_000 {
+                let allocator = allocator.as_ref().unwrap();
+
+                allocator
+                    .alloc_with_finalizer(layout, alloc_in_finalizer)
+                    .unwrap();
+            }
+        });
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+
+        arena.set_soft_limit(arena.get_size(), move |arena| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            arena.refresh();
+        });
+
+        // the finalizers grow the heap past the soft limit, but the callback
+        // has to wait for the refresh they run in to finish
+        arena.refresh();
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        FINALIZER_ALLOCATOR.with_borrow(|allocator| {
+            allocator.as_ref().unwrap().alloc(layout).unwrap();
+        });
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        FINALIZER_ALLOCATOR.set(None);
+    }
+
+    #[test]
+    fn weak_handles() {
+        let arena = Arena::new();
+        let allocator = Allocator::new(&arena);
+        let layout = Layout::new::<[u64; 8]>();
+        let mut weaks = vec![];
+
+        for i in 0..1_000 {
+            let ptr: NonNull<[u64; 8]> = allocator.alloc(layout).unwrap().cast();
+
+            if i % 100 == 0 {
+                Allocator::set_mark(ptr, arena.current_mark());
+            }
+
+            weaks.push((arena.new_weak(ptr), ptr));
+        }
+
+        arena.refresh();
+
+        for (i, (weak, ptr)) in weaks.iter().enumerate() {
+            if i % 100 == 0 {
+                assert_eq!(weak.get(), Some(*ptr));
+            } else {
+                assert!(weak.get().is_none());
+            }
+        }
+
+        weaks.retain(|(weak, _)| weak.get().is_some());
+        arena.prepare_evacuation();
+        let mark = arena.rotate_mark();
+
+        let moved: Vec<NonNull<[u64; 8]>> = weaks
+            .iter()
+            .map(|(weak, ptr)| {
+                let new_ptr = allocator.evacuate(*ptr);
+
+                Allocator::set_mark(new_ptr, mark);
+                assert_eq!(weak.clone().get(), Some(new_ptr));
+                new_ptr
+            })
+            .collect();
+
+        arena.refresh();
+
+        for ((weak, _), new_ptr) in weaks.iter().zip(moved) {
+            assert_eq!(weak.get(), Some(new_ptr));
+        }
+    }
+
+    #[test]
+    fn pinned_objects_stay_put() {
+        let arena = Arena::new();
+        let allocator = Allocator::new(&arena);
+        let layout = Layout::new::<[u64; 8]>();
+        let large: NonNull<u8> = allocator
+            .alloc(Layout::from_size_align(BLOCK_SIZE * 2, 8).unwrap())
+            .unwrap();
+        let mut survivors: Vec<NonNull<[u64; 8]>> = vec![];
+
+        assert!(Allocator::is_pinned(large));
+        Allocator::unpin(large);
+        assert!(Allocator::is_pinned(large));
+
+        for i in 0..10_000 {
+            let ptr: NonNull<[u64; 8]> = allocator.alloc(layout).unwrap().cast();
+
+            if i % 1_000 == 0 {
+                Allocator::set_mark(ptr, arena.current_mark());
+                survivors.push(ptr);
+            }
+        }
+
+        // pinning one object keeps its whole block from being selected
+        assert_eq!(Allocator::pin(survivors[0]), survivors[0]);
+        assert_eq!(Allocator::pin(survivors[0]), survivors[0]);
+        assert!(Allocator::is_pinned(survivors[0]));
+
+        arena.refresh();
+        arena.prepare_evacuation();
+
+        let mark = arena.rotate_mark();
+        let moved = survivors
+            .iter()
+            .filter(|ptr| allocator.evacuate(**ptr) != **ptr)
+            .count();
+
+        assert!(moved > 0);
+        assert_eq!(allocator.evacuate(survivors[0]), survivors[0]);
+        Allocator::set_mark(survivors[0], mark);
+
+        Allocator::unpin(survivors[0]);
+        assert!(!Allocator::is_pinned(survivors[0]));
+        assert!(!BlockMeta::from_header(Allocator::get_header(survivors[0])).has_pinned());
+    }
+
+    #[test]
+    fn sticky_mark_bits() {
+        let arena = Arena::new();
+        let allocator = Allocator::new(&arena);
+        let layout = Layout::new::<[u64; 8]>();
+        let mut old = vec![];
+
+        for i in 0..1_000 {
+            let ptr: NonNull<[u64; 8]> = allocator.alloc(layout).unwrap().cast();
+
+            if i % 10 == 0 {
+                Allocator::set_mark(ptr, arena.current_mark());
+                old.push(arena.new_weak(ptr));
+            }
+        }
+
+        arena.refresh_major();
+        let mut young = vec![];
+
+        for i in 0..10_000 {
+            let ptr: NonNull<[u64; 8]> = allocator.alloc(layout).unwrap().cast();
+
+            assert!(!allocator.is_old(ptr));
+
+            if i % 1_000 == 0 {
+                Allocator::set_mark(ptr, arena.current_mark());
+            }
+
+            young.push((arena.new_weak(ptr), i % 1_000 == 0));
+        }
+
+        // old objects aren't traced again, but a minor refresh keeps them
+        let size = arena.get_size();
+        arena.refresh_minor();
+
+        assert!(old.iter().all(|weak| weak.get().is_some()));
+        for (weak, marked) in young.iter() {
+            assert_eq!(weak.get().is_some(), *marked);
+        }
+
+        assert!(arena.get_size() < size);
+
+        // a major refresh with nothing marked frees everything once the
+        // allocator has handed its blocks back
+        drop(allocator);
+        arena.rotate_mark();
+        arena.refresh_major();
+
+        assert!(old.iter().all(|weak| weak.get().is_none()));
+        assert!(young.iter().all(|(weak, _)| weak.get().is_none()));
+        assert_eq!(arena.get_size(), 0);
+    }
+
+    #[test]
+    fn remember_old_to_young_pointers() {
+        let arena = Arena::new();
+        let allocator = Allocator::new(&arena);
+        let layout = Layout::new::<[u64; 8]>();
+        let large = Layout::from_size_align(BLOCK_SIZE * 2, 8).unwrap();
+        let mut old = vec![];
+
+        for i in 0..100 {
+            let ptr = allocator
+                .alloc(if i == 0 { large } else { layout })
+                .unwrap();
+
+            Allocator::set_mark(ptr, arena.current_mark());
+            old.push(ptr);
+        }
+
+        arena.refresh_major();
+
+        let young = allocator.alloc(layout).unwrap();
+        let other = allocator.alloc(layout).unwrap();
+
+        allocator.write_barrier(young, other);
+        allocator.write_barrier(old[1], old[2]);
+        assert_eq!(arena.dirty_cards().count(), 0);
+
+        let written = [old[0], old[10], old[11], old[90]];
+        for src in written {
+            allocator.write_barrier(src, young);
+        }
+
+        let mut found = vec![];
+        for card in arena.dirty_cards() {
+            card.for_each_object(|ptr, _| found.push(ptr));
+        }
+
+        for src in written {
+            assert!(found.contains(&src));
+        }
+
+        assert!(found.len() < old.len());
+        assert_eq!(arena.dirty_cards().count(), 0);
+
+        allocator.write_barrier(old[0], young);
+        allocator.write_barrier(old[50], young);
+        arena.refresh_minor();
+        assert_eq!(arena.dirty_cards().count(), 0);
+    }
+
+    #[test]
+    fn satb_barrier() {
+        let arena = Arena::new();
+        let allocator = Allocator::new(&arena);
+        let layout = Layout::new::<[u64; 8]>();
+        let before = allocator.alloc(layout).unwrap();
+        let marked = allocator.alloc(layout).unwrap();
+
+        allocator.satb_write_barrier(before);
+        assert!(arena.drain_satb().is_empty());
+
+        arena.start_marking();
+        Allocator::set_mark(marked, arena.current_mark());
+
+        // allocated black
+        let during = allocator.alloc(layout).unwrap();
+        assert_eq!(Allocator::get_mark(during), arena.current_mark());
+
+        allocator.satb_write_barrier(before);
+        allocator.satb_write_barrier(marked);
+        allocator.satb_write_barrier(during);
+        assert_eq!(arena.drain_satb(), vec![before]);
+
+        let addr = before.as_ptr() as usize;
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let other = Allocator::new(&arena);
+                let before = NonNull::new(addr as *mut u8).unwrap();
+
+                for _ in 0..1_000 {
+                    other.satb_write_barrier(before);
+                }
+            });
+        });
+
+        assert_eq!(arena.drain_satb().len(), 1_000);
+        arena.stop_marking();
+
+        let after = allocator.alloc(layout).unwrap();
+        assert_eq!(Allocator::get_mark(after), Mark::New);
+    }
 }
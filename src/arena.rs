@@ -1,14 +1,26 @@
 use super::allocate::GenerationalArena;
+use super::arena_config::ArenaConfig;
 use super::block_store::BlockStore;
-use super::constants::BLOCK_SIZE;
-use super::header::Mark;
+use super::card::Card;
+use super::header::{Header, Mark};
+use super::refresh::{RefreshBudget, RefreshProgress};
+use super::sweeper::Sweeper;
+use super::trace::{Trace, Tracer};
+use super::weak::Weak;
+use std::mem::align_of;
+use std::ptr::NonNull;
 use std::sync::atomic::{AtomicU8, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+
+type SoftLimitCallback = Arc<dyn Fn(&Arena) + Send + Sync>;
 
 #[derive(Clone)]
 pub struct Arena {
     block_store: Arc<BlockStore>,
     current_mark: Arc<AtomicU8>,
+    soft_limit_callback: Arc<Mutex<Option<SoftLimitCallback>>>,
+    // set with ArenaConfig::background_sweep, the thread exits with the last clone
+    _sweeper: Option<Arc<Sweeper>>,
 }
 
 impl Arena {
@@ -16,32 +28,220 @@ impl Arena {
         self.block_store.clone()
     }
 
+    pub(crate) fn get_block_store_ref(&self) -> &BlockStore {
+        &self.block_store
+    }
+
     pub fn get_current_mark_ref(&self) -> Arc<AtomicU8> {
         self.current_mark.clone()
     }
+
+    // Allocations that would take the arena over the hard limit fail with
+    // AllocErrorKind::HeapLimit. This overrides ArenaConfig::max_heap_size.
+    pub fn set_hard_limit(&self, bytes: usize) {
+        self.block_store.set_hard_limit(bytes);
+    }
+
+    // Once an allocation takes the arena over the soft limit the callback is
+    // run by the next allocation, before it allocates anything. No object is
+    // in flight at that point so the callback is free to collect and refresh.
+    // It fires again on the next growth after every refresh that leaves the
+    // arena over the limit. The callback is handed the arena, capturing a
+    // clone of it would keep the arena from ever being dropped.
+    pub fn set_soft_limit<F>(&self, bytes: usize, callback: F)
+    where
+        F: Fn(&Arena) + Send + Sync + 'static,
+    {
+        *self.soft_limit_callback.lock().unwrap() = Some(Arc::new(callback));
+        self.block_store.set_soft_limit(bytes);
+    }
+
+    // Whether ptr points into memory owned by the arena. See ArenaConfig::reserved_bytes
+    // for making this a range check.
+    pub fn contains<T>(&self, ptr: *const T) -> bool {
+        self.block_store.contains(ptr as *const u8)
+    }
+
+    // The index of the block holding ptr, only known with a reservation.
+    pub fn block_index<T>(&self, ptr: *const T) -> Option<usize> {
+        self.block_store.block_index(ptr as *const u8)
+    }
+
+    // The handle reads as None after the first refresh that finds the object
+    // unmarked. ptr must be an object allocated in this arena as a T.
+    pub fn new_weak<T>(&self, ptr: NonNull<T>) -> Weak<T> {
+        let align = std::cmp::max(align_of::<Header>(), align_of::<T>());
+        let header_offset = Header::object_offset(align);
+        let slot = self
+            .block_store
+            .push_weak(ptr.as_ptr() as *const u8, header_offset);
+
+        Weak::new(slot)
+    }
+
+    // With sticky mark bits the marks are only rotated for a major refresh,
+    // between them objects that are still New make up the nursery. A minor
+    // refresh only frees what was never marked, so old objects are kept
+    // without being traced again.
+    pub fn refresh_minor(&self) {
+        self.block_store.refresh(Mark::New);
+    }
+
+    // Frees everything not marked with the current mark, the whole heap must
+    // have been traced after rotating the mark.
+    pub fn refresh_major(&self) {
+        self.block_store.refresh(self.current_mark());
+    }
+
+    // A major refresh split into steps that each work within the budget,
+    // either a Duration or a number of blocks. The finalizers of dead objects
+    // and the release of emptied regions are split up the same way, only the
+    // weak handles are all cleared by the first step. Keep calling it until it
+    // returns Done before rotating the mark for the next collection, a
+    // refresh in the middle finishes whatever is left.
+    pub fn refresh_step(&self, budget: impl Into<RefreshBudget>) -> RefreshProgress {
+        self.block_store
+            .refresh_step(self.current_mark(), budget.into())
+    }
+
+    // Whether a refresh still has blocks waiting to be swept, by refresh_step
+    // or the background sweeper.
+    pub fn is_sweeping(&self) -> bool {
+        self.block_store.is_sweeping()
+    }
+
+    // While marking, allocators log the references passed to
+    // Allocator::satb_write_barrier and new objects are allocated already
+    // marked with the current mark.
+    pub fn start_marking(&self) {
+        self.block_store.set_marking(true);
+    }
+
+    pub fn stop_marking(&self) {
+        self.block_store.set_marking(false);
+    }
+
+    pub fn is_marking(&self) -> bool {
+        self.block_store.is_marking()
+    }
+
+    // Takes every reference logged by the SATB barrier so far. A marker keeps
+    // draining until this comes back empty with the mutators stopped.
+    pub fn drain_satb(&self) -> Vec<NonNull<u8>> {
+        self.block_store
+            .take_satb()
+            .into_iter()
+            .filter_map(|object| NonNull::new(object as *mut u8))
+            .collect()
+    }
+
+    // A full collection, rotates the mark, marks everything reachable from the
+    // roots with it and then refreshes. Every object has to have been
+    // allocated with the layout of the type it is traced as.
+    pub fn collect(&self, roots: &[&dyn Trace]) {
+        let mut tracer = Tracer::new(self.rotate_mark());
+
+        for root in roots {
+            root.trace(&mut tracer);
+        }
+
+        tracer.drain();
+        self.refresh();
+    }
+
+    /// Like collect, but the marking is split between the given number of
+    /// worker threads. Objects may be traced from any of the workers.
+    ///
+    /// # Safety
+    ///
+    /// Trace has no Sync bound, so the caller has to make sure that every type
+    /// reachable from the roots can be traced from another thread, as if it
+    /// were Sync, and that nothing else touches those objects while this runs.
+    pub unsafe fn collect_parallel(&self, roots: &[&dyn Trace], workers: usize) {
+        let mut tracer = Tracer::new(self.rotate_mark());
+
+        for root in roots {
+            root.trace(&mut tracer);
+        }
+
+        tracer.drain_parallel(workers);
+        self.refresh();
+    }
+
+    // Takes the cards dirtied by Allocator::write_barrier since the last refresh
+    // or call to this, which clears them.
+    pub fn dirty_cards(&self) -> impl Iterator<Item = Card> {
+        self.block_store.take_dirty_cards().into_iter()
+    }
+
+    // Given a pointer anywhere into an object, returns where the object starts
+    // along with its header. Works for small, medium and large objects.
+    pub fn find_object<T>(&self, ptr: *const T) -> Option<(NonNull<u8>, *const Header)> {
+        let header = self.block_store.find_object(ptr as *const u8)?;
+        let object = Header::object_start(header) as *mut u8;
+
+        Some((NonNull::new(object).unwrap(), header))
+    }
+
+    // Calls f with the start and header of every object in the arena, this
+    // includes dead objects that haven't been swept. Nothing should allocate
+    // while the walk runs, and f must not refresh the arena.
+    pub fn for_each_object<F>(&self, mut f: F)
+    where
+        F: FnMut(NonNull<u8>, &Header),
+    {
+        self.block_store.for_each_object(&mut |header| {
+            let object = Header::object_start(header) as *mut u8;
+
+            f(NonNull::new(object).unwrap(), unsafe { &*header });
+        });
+    }
+
+    pub fn run_soft_limit_callback(&self) {
+        if !self.block_store.take_soft_limit_hit() {
+            return;
+        }
+
+        let callback = self.soft_limit_callback.lock().unwrap().clone();
+
+        if let Some(callback) = callback {
+            callback(self);
+        }
+    }
 }
 
 impl GenerationalArena for Arena {
     type Mark = Mark;
+    type Config = ArenaConfig;
 
-    // TODO: take free rate, and a init size as config
     fn new() -> Self {
+        Self::new_with_config(ArenaConfig::default())
+    }
+
+    fn new_with_config(config: ArenaConfig) -> Self {
+        let block_store = Arc::new(BlockStore::with_config(config));
+        let sweeper = block_store
+            .get_sweep_signal()
+            .map(|signal| Arc::new(Sweeper::spawn(Arc::downgrade(&block_store), signal)));
+
         Self {
-            block_store: Arc::new(BlockStore::new()),
+            block_store,
             current_mark: Arc::new(AtomicU8::new(Mark::Red as u8)),
+            soft_limit_callback: Arc::new(Mutex::new(None)),
+            _sweeper: sweeper,
         }
     }
 
     fn refresh(&self) {
-        self.block_store.refresh(self.current_mark());
+        self.refresh_major();
     }
 
-    fn get_size(&self) -> usize {
-        // TODO: don't count free blocks
-        let block_space = self.block_store.block_count() * BLOCK_SIZE;
-        let large_space = self.block_store.count_large_space();
+    fn prepare_evacuation(&self) -> usize {
+        self.block_store.select_evacuation_candidates()
+    }
 
-        block_space + large_space
+    fn get_size(&self) -> usize {
+        self.block_store.get_size()
     }
 
     fn current_mark(&self) -> Self::Mark {
@@ -0,0 +1,151 @@
+#[derive(Debug, Clone)]
+pub struct ArenaConfig {
+    initial_blocks: usize,
+    max_heap_size: usize,
+    free_blocks_retained: usize,
+    large_bytes_retained: usize,
+    lazy_sweep: bool,
+    background_sweep: bool,
+    sweep_threads: usize,
+    head_stash_size: usize,
+    decommit_free_blocks: bool,
+    reserved_bytes: usize,
+}
+
+impl Default for ArenaConfig {
+    fn default() -> Self {
+        Self {
+            initial_blocks: 0,
+            max_heap_size: usize::MAX,
+            free_blocks_retained: 0,
+            large_bytes_retained: 0,
+            lazy_sweep: false,
+            background_sweep: false,
+            sweep_threads: 1,
+            head_stash_size: 0,
+            decommit_free_blocks: false,
+            reserved_bytes: 0,
+        }
+    }
+}
+
+impl ArenaConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Blocks allocated up front and put straight into the free list.
+    // This is best effort, if the system is out of memory fewer blocks are made.
+    pub fn initial_blocks(mut self, blocks: usize) -> Self {
+        self.initial_blocks = blocks;
+        self
+    }
+
+    // Allocations that would push the arena size over this many bytes fail
+    // with AllocErrorKind::HeapLimit.
+    pub fn max_heap_size(mut self, bytes: usize) -> Self {
+        self.max_heap_size = bytes;
+        self
+    }
+
+    // The minimum number of free blocks a refresh keeps around instead of
+    // giving their regions back to the system.
+    pub fn free_blocks_retained(mut self, blocks: usize) -> Self {
+        self.free_blocks_retained = blocks;
+        self
+    }
+
+    // Up to this many bytes of dead large objects are kept after a refresh
+    // to be reused by later large allocations, anything over it is released.
+    pub fn large_bytes_retained(mut self, bytes: usize) -> Self {
+        self.large_bytes_retained = bytes;
+        self
+    }
+
+    // Instead of sweeping every block, refresh only records the mark and each
+    // block is swept the first time it's handed out. Unswept blocks still
+    // count towards the arena size until they are swept.
+    pub fn lazy_sweep(mut self, lazy: bool) -> Self {
+        self.lazy_sweep = lazy;
+        self
+    }
+
+    // Refresh only queues the blocks up and a thread owned by the arena sweeps
+    // them afterwards, along with giving free regions back. Allocators can take
+    // blocks the thread hasn't gotten to yet, sweeping them on the spot.
+    pub fn background_sweep(mut self, background: bool) -> Self {
+        self.background_sweep = background;
+        self
+    }
+
+    // The number of threads an eager refresh splits the blocks between.
+    pub fn sweep_threads(mut self, threads: usize) -> Self {
+        self.sweep_threads = threads;
+        self
+    }
+
+    // Each allocator fetches up to this many blocks at once to allocate into,
+    // recycled blocks first and then free ones, and hands its full blocks back
+    // in batches of the same size. With 0 blocks are fetched one at a time.
+    pub fn head_stash_size(mut self, blocks: usize) -> Self {
+        self.head_stash_size = blocks;
+        self
+    }
+
+    // Free blocks left after a refresh have their pages given back to the OS,
+    // their address space stays reserved until the block is reused. Only
+    // blocks from a reservation are decommitted, see reserved_bytes.
+    pub fn decommit_free_blocks(mut self, decommit: bool) -> Self {
+        self.decommit_free_blocks = decommit;
+        self
+    }
+
+    // Reserve this much contiguous address space up front and commit regions
+    // out of it, which makes Arena::contains a range check. Blocks can't be
+    // made once the reservation is used up. Only supported on Linux, elsewhere
+    // (or if reserving fails) regions come from the system allocator.
+    pub fn reserved_bytes(mut self, bytes: usize) -> Self {
+        self.reserved_bytes = bytes;
+        self
+    }
+
+    pub fn get_initial_blocks(&self) -> usize {
+        self.initial_blocks
+    }
+
+    pub fn get_max_heap_size(&self) -> usize {
+        self.max_heap_size
+    }
+
+    pub fn get_free_blocks_retained(&self) -> usize {
+        self.free_blocks_retained
+    }
+
+    pub fn get_large_bytes_retained(&self) -> usize {
+        self.large_bytes_retained
+    }
+
+    pub fn get_lazy_sweep(&self) -> bool {
+        self.lazy_sweep
+    }
+
+    pub fn get_background_sweep(&self) -> bool {
+        self.background_sweep
+    }
+
+    pub fn get_sweep_threads(&self) -> usize {
+        self.sweep_threads
+    }
+
+    pub fn get_head_stash_size(&self) -> usize {
+        self.head_stash_size
+    }
+
+    pub fn get_decommit_free_blocks(&self) -> bool {
+        self.decommit_free_blocks
+    }
+
+    pub fn get_reserved_bytes(&self) -> usize {
+        self.reserved_bytes
+    }
+}
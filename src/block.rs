@@ -1,4 +1,6 @@
+#[cfg(test)]
 use super::constants::BLOCK_SIZE;
+use super::error::AllocErrorKind;
 use std::alloc::{alloc, dealloc, Layout};
 use std::ptr::NonNull;
 
@@ -7,26 +9,23 @@ pub struct Block {
     layout: Layout,
 }
 
+unsafe impl Send for Block {}
+
 impl Block {
-    pub fn default() -> Result<Block, ()> {
+    #[cfg(test)]
+    pub fn default() -> Result<Block, AllocErrorKind> {
         let layout = Layout::from_size_align(BLOCK_SIZE, BLOCK_SIZE).unwrap();
 
         Self::new(layout)
     }
 
-    pub fn new(layout: Layout) -> Result<Block, ()> {
+    pub fn new(layout: Layout) -> Result<Block, AllocErrorKind> {
         Ok(Block {
             ptr: Self::alloc_block(layout)?,
             layout,
         })
     }
 
-    pub fn at_offset(&self, offset: usize) -> *const u8 {
-        debug_assert!(offset < BLOCK_SIZE);
-
-        unsafe { self.ptr.as_ptr().add(offset) }
-    }
-
     pub fn as_ptr(&self) -> *const u8 {
         self.ptr.as_ptr()
     }
@@ -35,12 +34,16 @@ impl Block {
         self.layout.size()
     }
 
-    fn alloc_block(layout: Layout) -> Result<NonNull<u8>, ()> {
+    pub fn fits(&self, layout: Layout) -> bool {
+        self.layout.size() >= layout.size() && self.layout.align() >= layout.align()
+    }
+
+    fn alloc_block(layout: Layout) -> Result<NonNull<u8>, AllocErrorKind> {
         unsafe {
             let ptr = alloc(layout);
 
             if ptr.is_null() {
-                Err(())
+                Err(AllocErrorKind::OutOfMemory)
             } else {
                 Ok(NonNull::new_unchecked(ptr))
             }
@@ -0,0 +1,389 @@
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::sync::OnceLock;
+
+const NIL: u32 = u32::MAX;
+// segment n holds SEGMENT_BASE << n nodes
+const SEGMENT_BASE: usize = 64;
+const SEGMENT_COUNT: usize = 26;
+
+struct Node<T> {
+    value: UnsafeCell<MaybeUninit<T>>,
+    next: AtomicU32,
+}
+
+type Segment<T> = OnceLock<Box<[Node<T>]>>;
+
+// A lock-free stack of blocks. Values live in a pool of nodes that is never
+// shrunk, so a node can be read after it has been popped by another thread.
+// The heads pack a node index together with a tag that is bumped on every
+// change, which keeps a pop from succeeding against a head that was popped
+// and pushed again in between (the ABA problem).
+pub struct BlockList<T> {
+    head: AtomicU64,
+    // nodes whose values have been popped, ready to be reused
+    spare: AtomicU64,
+    next_index: AtomicU32,
+    segments: Box<[Segment<T>]>,
+}
+
+unsafe impl<T: Send> Send for BlockList<T> {}
+unsafe impl<T: Send> Sync for BlockList<T> {}
+
+impl<T> BlockList<T> {
+    pub fn new() -> Self {
+        Self {
+            head: AtomicU64::new(Self::pack(NIL, 0)),
+            spare: AtomicU64::new(Self::pack(NIL, 0)),
+            next_index: AtomicU32::new(0),
+            segments: (0..SEGMENT_COUNT).map(|_| OnceLock::new()).collect(),
+        }
+    }
+
+    pub fn push(&self, value: T) {
+        let index = self
+            .pop_index(&self.spare)
+            .unwrap_or_else(|| self.new_index());
+
+        unsafe { (*self.node(index).value.get()).write(value) };
+        self.push_index(&self.head, index);
+    }
+
+    pub fn pop(&self) -> Option<T> {
+        let index = self.pop_index(&self.head)?;
+        // the node is ours until it is handed back to the spare stack
+        let value = unsafe { (*self.node(index).value.get()).assume_init_read() };
+
+        self.push_index(&self.spare, index);
+        Some(value)
+    }
+
+    // Pops every value in a single exchange, the most recently pushed first.
+    pub fn take_all(&self) -> Vec<T> {
+        self.pop_n(usize::MAX)
+    }
+
+    // Pops up to n values in a single exchange, the most recently pushed first.
+    pub fn pop_n(&self, n: usize) -> Vec<T> {
+        let indexes = self.pop_chain(&self.head, n);
+        let values = indexes
+            .iter()
+            .map(|index| unsafe { (*self.node(*index).value.get()).assume_init_read() })
+            .collect();
+
+        self.push_chain_indexes(&self.spare, &indexes);
+        values
+    }
+
+    // Links the values up into a chain first and then splices it onto the
+    // stack in a single exchange. The last value ends up on top, as if they
+    // had been pushed one by one.
+    pub fn push_chain(&self, values: impl IntoIterator<Item = T>) {
+        let values: Vec<T> = values.into_iter().collect();
+        let mut indexes = self.pop_chain(&self.spare, values.len());
+
+        while indexes.len() < values.len() {
+            indexes.push(self.new_index());
+        }
+
+        for (index, value) in indexes.iter().zip(values) {
+            unsafe { (*self.node(*index).value.get()).write(value) };
+        }
+
+        indexes.reverse();
+        self.push_chain_indexes(&self.head, &indexes);
+    }
+
+    // Calls f with every value without taking any, the most recently pushed
+    // first. A node is only ever rewritten after it has been popped, so pushes
+    // may race with the walk, which then only sees what was pushed before it
+    // started. Nothing may pop until it is done.
+    pub fn for_each(&self, mut f: impl FnMut(&T)) {
+        let (mut index, _) = Self::unpack(self.head.load(Ordering::Acquire));
+
+        while index != NIL {
+            let node = self.node(index);
+
+            f(unsafe { (*node.value.get()).assume_init_ref() });
+            index = node.next.load(Ordering::Relaxed);
+        }
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        Self::unpack(self.head.load(Ordering::Acquire)).0 == NIL
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        let mut len = 0;
+
+        self.for_each(|_| len += 1);
+        len
+    }
+
+    fn push_index(&self, stack: &AtomicU64, index: u32) {
+        let node = self.node(index);
+        let mut head = stack.load(Ordering::Acquire);
+
+        loop {
+            let (next, tag) = Self::unpack(head);
+
+            node.next.store(next, Ordering::Relaxed);
+
+            match stack.compare_exchange_weak(
+                head,
+                Self::pack(index, tag.wrapping_add(1)),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    fn pop_index(&self, stack: &AtomicU64) -> Option<u32> {
+        let mut head = stack.load(Ordering::Acquire);
+
+        loop {
+            let (index, tag) = Self::unpack(head);
+
+            if index == NIL {
+                return None;
+            }
+
+            // may be stale if the node was popped meanwhile, the tag then
+            // makes the exchange fail
+            let next = self.node(index).next.load(Ordering::Relaxed);
+
+            match stack.compare_exchange_weak(
+                head,
+                Self::pack(next, tag.wrapping_add(1)),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return Some(index),
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    // The first index ends up on top.
+    fn push_chain_indexes(&self, stack: &AtomicU64, indexes: &[u32]) {
+        let (Some(first), Some(last)) = (indexes.first(), indexes.last()) else {
+            return;
+        };
+
+        for pair in indexes.windows(2) {
+            self.node(pair[0]).next.store(pair[1], Ordering::Relaxed);
+        }
+
+        let last = self.node(*last);
+        let mut head = stack.load(Ordering::Acquire);
+
+        loop {
+            let (next, tag) = Self::unpack(head);
+
+            last.next.store(next, Ordering::Relaxed);
+
+            match stack.compare_exchange_weak(
+                head,
+                Self::pack(*first, tag.wrapping_add(1)),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    // Like pop_index for up to n nodes. The chain walked may be stale if any
+    // of it was popped meanwhile, the tag then makes the exchange fail.
+    fn pop_chain(&self, stack: &AtomicU64, n: usize) -> Vec<u32> {
+        let mut head = stack.load(Ordering::Acquire);
+
+        loop {
+            let (first, tag) = Self::unpack(head);
+            let mut indexes = vec![];
+            let mut index = first;
+
+            while index != NIL && indexes.len() < n {
+                indexes.push(index);
+                index = self.node(index).next.load(Ordering::Relaxed);
+            }
+
+            if indexes.is_empty() {
+                return indexes;
+            }
+
+            match stack.compare_exchange_weak(
+                head,
+                Self::pack(index, tag.wrapping_add(1)),
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => return indexes,
+                Err(current) => head = current,
+            }
+        }
+    }
+
+    fn new_index(&self) -> u32 {
+        let index = self.next_index.fetch_add(1, Ordering::Relaxed);
+        let (segment, _) = Self::locate(index);
+
+        assert!(segment < SEGMENT_COUNT, "block list is full");
+        self.segments[segment].get_or_init(|| {
+            (0..SEGMENT_BASE << segment)
+                .map(|_| Node {
+                    value: UnsafeCell::new(MaybeUninit::uninit()),
+                    next: AtomicU32::new(NIL),
+                })
+                .collect()
+        });
+
+        index
+    }
+
+    fn node(&self, index: u32) -> &Node<T> {
+        let (segment, offset) = Self::locate(index);
+
+        &self.segments[segment].get().unwrap()[offset]
+    }
+
+    fn locate(index: u32) -> (usize, usize) {
+        let index = index as usize;
+        let segment = (index / SEGMENT_BASE + 1).ilog2() as usize;
+        let offset = index - SEGMENT_BASE * ((1 << segment) - 1);
+
+        (segment, offset)
+    }
+
+    fn pack(index: u32, tag: u32) -> u64 {
+        ((tag as u64) << 32) | index as u64
+    }
+
+    fn unpack(head: u64) -> (u32, u32) {
+        (head as u32, (head >> 32) as u32)
+    }
+}
+
+impl<T> Default for BlockList<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for BlockList<T> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::thread;
+
+    #[test]
+    fn locate_nodes() {
+        assert_eq!(BlockList::<usize>::locate(0), (0, 0));
+        assert_eq!(BlockList::<usize>::locate(63), (0, 63));
+        assert_eq!(BlockList::<usize>::locate(64), (1, 0));
+        assert_eq!(BlockList::<usize>::locate(191), (1, 127));
+        assert_eq!(BlockList::<usize>::locate(192), (2, 0));
+    }
+
+    #[test]
+    fn push_and_pop() {
+        let list = BlockList::new();
+
+        list.push_chain(0..200);
+        assert_eq!(list.len(), 200);
+        assert_eq!(list.pop(), Some(199));
+
+        // popped nodes are reused
+        list.push(300);
+        assert_eq!(list.next_index.load(Ordering::Relaxed), 200);
+        assert_eq!(list.take_all().len(), 200);
+        assert_eq!(list.pop(), None);
+    }
+
+    #[test]
+    fn chains_and_batches() {
+        let list = BlockList::new();
+
+        list.push_chain(0..100);
+        assert_eq!(list.pop_n(3), vec![99, 98, 97]);
+
+        // the nodes popped in the batch are reused by the next chain
+        list.push_chain(100..110);
+        assert_eq!(list.next_index.load(Ordering::Relaxed), 107);
+        assert_eq!(list.pop(), Some(109));
+        assert_eq!(list.pop_n(200).len(), 106);
+        assert!(list.pop_n(1).is_empty());
+    }
+
+    #[test]
+    fn walk_without_popping() {
+        let list = BlockList::new();
+
+        list.push_chain(0..100);
+
+        let mut walked = vec![];
+        list.for_each(|value| walked.push(*value));
+
+        assert_eq!(walked, (0..100).rev().collect::<Vec<_>>());
+        assert_eq!(list.len(), 100);
+        assert!(!list.is_empty());
+
+        list.take_all();
+        assert!(list.is_empty());
+    }
+
+    #[test]
+    fn concurrent_push_and_pop() {
+        let list = BlockList::new();
+        let threads = 8;
+        let per_thread: usize = 10_000;
+
+        let popped: Vec<usize> = thread::scope(|scope| {
+            let handles: Vec<_> = (0..threads)
+                .map(|t| {
+                    let list = &list;
+
+                    scope.spawn(move || {
+                        let mut popped = vec![];
+
+                        for i in 0..per_thread {
+                            list.push(t * per_thread + i);
+
+                            if i.is_multiple_of(2) {
+                                popped.extend(list.pop());
+                            }
+                        }
+
+                        popped
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect()
+        });
+
+        let mut seen: HashSet<usize> = popped.into_iter().collect();
+        let left = list.take_all();
+        let total = seen.len() + left.len();
+
+        seen.extend(left);
+        assert_eq!(total, threads * per_thread);
+        assert_eq!(seen.len(), threads * per_thread);
+    }
+}
@@ -3,10 +3,27 @@ use super::constants;
 use super::header::Header;
 use super::header::Mark;
 use super::size_class::SizeClass;
-use std::sync::atomic::{AtomicU8, Ordering};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU16, AtomicU8, Ordering};
+
+// The last few line marks fall inside of the block metadata itself, so
+// they are free to hold the block mark, the block flags and the number of
+// pinned objects in the block (two bytes).
+const BLOCK_MARK: usize = constants::LINE_COUNT - 1;
+const BLOCK_FLAGS: usize = constants::LINE_COUNT - 2;
+const PIN_COUNT: usize = constants::LINE_COUNT - 4;
+const DATA_LINES: usize = constants::BLOCK_CAPACITY / constants::LINE_SIZE;
+const LINE_GRANULES: usize = constants::LINE_SIZE / constants::OBJECT_GRANULE;
+
+const EVACUATE_FLAG: u8 = 0b0000_0001;
+// set while any card of the block is dirty
+const DIRTY_FLAG: u8 = 0b0000_0010;
 
 pub struct BlockMeta {
     lines: *const [AtomicU8; constants::LINE_COUNT],
+    // a set bit means an allocation starts at that granule
+    starts: *const [AtomicU8; constants::START_BITMAP_SIZE],
+    cards: *const [AtomicU8; constants::CARD_COUNT],
 }
 
 impl BlockMeta {
@@ -17,13 +34,21 @@ impl BlockMeta {
     }
 
     pub fn from_block(block_ptr: *const u8) -> Self {
-        debug_assert!((block_ptr as usize % constants::BLOCK_SIZE) == 0);
+        debug_assert!((block_ptr as usize).is_multiple_of(constants::BLOCK_SIZE));
 
         Self {
             lines: unsafe {
                 block_ptr.add(constants::LINE_MARK_START)
                     as *const [AtomicU8; constants::LINE_COUNT]
             },
+            starts: unsafe {
+                block_ptr.add(constants::START_BITMAP_START)
+                    as *const [AtomicU8; constants::START_BITMAP_SIZE]
+            },
+            cards: unsafe {
+                block_ptr.add(constants::CARD_TABLE_START)
+                    as *const [AtomicU8; constants::CARD_COUNT]
+            },
         }
     }
 
@@ -48,7 +73,7 @@ impl BlockMeta {
         if size_class == SizeClass::Small {
             self.set_line(line, mark);
         } else {
-            let num_lines = size / constants::LINE_SIZE as u16;
+            let num_lines = size / constants::LINE_SIZE as u32;
 
             for i in 0..num_lines {
                 self.set_line(line + i as usize, mark);
@@ -58,16 +83,185 @@ impl BlockMeta {
         self.set_block(mark);
     }
 
+    // Objects can only start in marked lines, so the starts in every line
+    // that gets freed belong to dead objects.
     pub fn free_unmarked(&self, mark: Mark) {
-        for i in 0..constants::LINE_COUNT {
-            if self.get_line(i) != mark {
+        let block_dead = !self.get_block().survives(mark);
+
+        // objects left pinned in a dead block died without being unpinned
+        if block_dead {
+            self.pin_count().store(0, Ordering::Relaxed);
+        }
+
+        for i in (0..DATA_LINES).chain(std::iter::once(BLOCK_MARK)) {
+            if !self.get_line(i).survives(mark) {
                 self.set_line(i, Mark::New);
+
+                if i < DATA_LINES {
+                    if !block_dead && self.has_pinned() {
+                        self.unpin_dead(i);
+                    }
+
+                    self.clear_line_starts(i);
+                }
+            }
+        }
+    }
+
+    pub fn set_object_start(&self, offset: usize) {
+        let granule = offset / constants::OBJECT_GRANULE;
+
+        self.start_byte(granule / 8)
+            .fetch_or(1 << (granule % 8), Ordering::Relaxed);
+    }
+
+    // Finds the closest allocation starting at or before offset.
+    pub fn find_object_start(&self, offset: usize) -> Option<usize> {
+        debug_assert!(offset < constants::BLOCK_CAPACITY);
+
+        let granule = offset / constants::OBJECT_GRANULE;
+        let mut index = granule / 8;
+        // only the bits at or below the granule count in the first byte
+        let mut bits = self.start_byte(index).load(Ordering::Relaxed) & (0xFF >> (7 - granule % 8));
+
+        loop {
+            if bits != 0 {
+                let bit = 7 - bits.leading_zeros() as usize;
+
+                return Some((index * 8 + bit) * constants::OBJECT_GRANULE);
+            }
+
+            if index == 0 {
+                return None;
+            }
+
+            index -= 1;
+            bits = self.start_byte(index).load(Ordering::Relaxed);
+        }
+    }
+
+    // The offsets of every allocation starting within the range, lowest first.
+    pub fn object_starts(&self, range: Range<usize>) -> impl Iterator<Item = usize> + '_ {
+        let byte_size = constants::OBJECT_GRANULE * 8;
+        let first = range.start / byte_size;
+        let last = range.end.min(constants::BLOCK_CAPACITY).div_ceil(byte_size);
+
+        (first..last)
+            .flat_map(move |index| {
+                let bits = self.start_byte(index).load(Ordering::Relaxed);
+
+                (0..8)
+                    .filter(move |bit| bits & (1 << bit) != 0)
+                    .map(move |bit| (index * 8 + bit) * constants::OBJECT_GRANULE)
+            })
+            .filter(move |offset| range.contains(offset))
+    }
+
+    // The objects starting in a freed line are dead, any of them that were
+    // still pinned stop holding the block in place.
+    fn unpin_dead(&self, line: usize) {
+        let block = unsafe { (self.lines as *const u8).sub(constants::LINE_MARK_START) };
+        let start = line * constants::LINE_SIZE;
+
+        for offset in self.object_starts(start..start + constants::LINE_SIZE) {
+            if Header::unpin(unsafe { block.add(offset) } as *const Header) {
+                self.unpin();
             }
         }
     }
 
+    fn clear_line_starts(&self, line: usize) {
+        let first = line * LINE_GRANULES / 8;
+
+        for i in first..(first + LINE_GRANULES / 8) {
+            self.start_byte(i).store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn start_byte(&self, index: usize) -> &AtomicU8 {
+        debug_assert!(index < constants::START_BITMAP_SIZE);
+
+        unsafe { &(&*self.starts)[index] }
+    }
+
+    pub fn count_marked_lines(&self) -> usize {
+        (0..DATA_LINES)
+            .filter(|i| !self.get_line(*i).is_new())
+            .count()
+    }
+
     pub fn get_block(&self) -> Mark {
-        self.get_line(constants::LINE_COUNT - 1)
+        self.get_line(BLOCK_MARK)
+    }
+
+    pub fn is_evacuating(&self) -> bool {
+        self.get_flags() & EVACUATE_FLAG != 0
+    }
+
+    pub fn set_evacuating(&self, evacuating: bool) {
+        if evacuating {
+            self.mark_at(BLOCK_FLAGS)
+                .fetch_or(EVACUATE_FLAG, Ordering::Release);
+        } else {
+            self.mark_at(BLOCK_FLAGS)
+                .fetch_and(!EVACUATE_FLAG, Ordering::Release);
+        }
+    }
+
+    // Dirties the card holding the header, returns true if the block had no
+    // dirty cards before.
+    pub fn dirty_card(&self, header: *const Header) -> bool {
+        let card = (header as usize % constants::BLOCK_SIZE) / constants::CARD_SIZE;
+
+        self.card_at(card).store(1, Ordering::Release);
+
+        self.get_flags() & DIRTY_FLAG == 0
+            && self
+                .mark_at(BLOCK_FLAGS)
+                .fetch_or(DIRTY_FLAG, Ordering::AcqRel)
+                & DIRTY_FLAG
+                == 0
+    }
+
+    // Clears every card of the block, returning the indexes of the dirty ones.
+    pub fn take_dirty_cards(&self) -> Vec<usize> {
+        self.mark_at(BLOCK_FLAGS)
+            .fetch_and(!DIRTY_FLAG, Ordering::AcqRel);
+
+        (0..constants::CARD_COUNT)
+            .filter(|card| self.card_at(*card).swap(0, Ordering::AcqRel) != 0)
+            .collect()
+    }
+
+    fn card_at(&self, card: usize) -> &AtomicU8 {
+        debug_assert!(card < constants::CARD_COUNT);
+
+        unsafe { &(&*self.cards)[card] }
+    }
+
+    pub fn pin(&self) {
+        self.pin_count().fetch_add(1, Ordering::AcqRel);
+    }
+
+    pub fn unpin(&self) {
+        self.pin_count().fetch_sub(1, Ordering::AcqRel);
+    }
+
+    pub fn has_pinned(&self) -> bool {
+        self.pin_count().load(Ordering::Acquire) != 0
+    }
+
+    fn pin_count(&self) -> &AtomicU16 {
+        unsafe {
+            let ptr = (self.lines as *const u8).add(PIN_COUNT) as *const AtomicU16;
+
+            debug_assert!((ptr as usize).is_multiple_of(align_of::<AtomicU16>()));
+            &*ptr
+        }
+    }
+
+    fn get_flags(&self) -> u8 {
+        self.mark_at(BLOCK_FLAGS).load(Ordering::Acquire)
     }
 
     fn get_line(&self, index: usize) -> Mark {
@@ -85,13 +279,23 @@ impl BlockMeta {
     }
 
     pub fn set_block(&self, mark: Mark) {
-        self.set_line(constants::LINE_COUNT - 1, mark)
+        self.set_line(BLOCK_MARK, mark)
     }
 
     pub fn reset(&self) {
         for i in 0..constants::LINE_COUNT {
             self.set_line(i, Mark::New);
         }
+
+        self.pin_count().store(0, Ordering::Relaxed);
+
+        for i in 0..constants::START_BITMAP_SIZE {
+            self.start_byte(i).store(0, Ordering::Relaxed);
+        }
+
+        for i in 0..constants::CARD_COUNT {
+            self.card_at(i).store(0, Ordering::Relaxed);
+        }
     }
 
     pub fn find_next_available_hole(
@@ -101,7 +305,7 @@ impl BlockMeta {
     ) -> Option<(usize, usize)> {
         let mut count = 0;
         let starting_line = starting_at / constants::LINE_SIZE;
-        let lines_required = (alloc_size + constants::LINE_SIZE - 1) / constants::LINE_SIZE;
+        let lines_required = alloc_size.div_ceil(constants::LINE_SIZE);
         let mut end = starting_line;
 
         for index in (0..starting_line).rev() {
@@ -277,4 +481,113 @@ mod tests {
         let meta = BlockMeta::from_header(header);
         assert_eq!(meta.get_block(), Mark::Red);
     }
+
+    #[test]
+    fn find_object_starts() {
+        let block = Block::default().unwrap();
+        let meta = BlockMeta::new(block.as_ptr());
+        let line = constants::LINE_SIZE;
+
+        assert_eq!(meta.find_object_start(line * 3), None);
+
+        meta.set_object_start(0);
+        meta.set_object_start(line + 8);
+        meta.set_object_start(line * 2 + 64);
+        meta.set_line(0, Mark::Red);
+        meta.set_line(2, Mark::Red);
+
+        assert_eq!(meta.find_object_start(0), Some(0));
+        assert_eq!(meta.find_object_start(line), Some(0));
+        assert_eq!(meta.find_object_start(line + 8), Some(line + 8));
+        assert_eq!(meta.find_object_start(line * 2 + 63), Some(line + 8));
+        assert_eq!(meta.find_object_start(line * 10), Some(line * 2 + 64));
+
+        let starts: Vec<usize> = meta.object_starts(0..constants::BLOCK_CAPACITY).collect();
+        assert_eq!(starts, vec![0, line + 8, line * 2 + 64]);
+
+        let starts: Vec<usize> = meta.object_starts(8..(line * 2 + 64)).collect();
+        assert_eq!(starts, vec![line + 8]);
+
+        // line 1 isn't marked so the object starting in it is dead
+        meta.free_unmarked(Mark::Red);
+        assert_eq!(meta.find_object_start(line * 2 + 63), Some(0));
+        assert_eq!(meta.find_object_start(line * 10), Some(line * 2 + 64));
+    }
+
+    #[test]
+    fn dirty_cards() {
+        let block = Block::default().unwrap();
+        let meta = BlockMeta::new(block.as_ptr());
+        let header = |offset: usize| unsafe { block.as_ptr().add(offset) as *const Header };
+
+        assert!(meta.dirty_card(header(0)));
+        assert!(!meta.dirty_card(header(constants::CARD_SIZE - 16)));
+        assert!(!meta.dirty_card(header(constants::CARD_SIZE * 3)));
+        assert_eq!(meta.take_dirty_cards(), vec![0, 3]);
+
+        assert!(meta.take_dirty_cards().is_empty());
+        assert!(meta.dirty_card(header(constants::CARD_SIZE)));
+    }
+
+    #[test]
+    fn pins_reset_with_dead_block() {
+        let block = Block::default().unwrap();
+        let meta = BlockMeta::new(block.as_ptr());
+
+        meta.pin();
+        meta.pin();
+        meta.unpin();
+        meta.set_block(Mark::Red);
+        meta.free_unmarked(Mark::Red);
+        assert!(meta.has_pinned());
+
+        meta.free_unmarked(Mark::Green);
+        assert!(!meta.has_pinned());
+    }
+
+    #[test]
+    fn pins_of_dead_objects_dropped_from_live_block() {
+        let block = Block::default().unwrap();
+        let meta = BlockMeta::new(block.as_ptr());
+        let header = |offset: usize| unsafe {
+            let ptr = block.as_ptr().add(offset) as *mut Header;
+
+            std::ptr::write(ptr, Header::new(SizeClass::Small, 16, 8));
+            meta.set_object_start(offset);
+            ptr as *const Header
+        };
+        let live = header(0);
+        let dead = header(constants::LINE_SIZE);
+
+        for object in [live, dead] {
+            assert_eq!(Header::pin(object), Ok(false));
+            meta.pin();
+        }
+
+        Header::set_mark(live, Mark::Red);
+        meta.free_unmarked(Mark::Red);
+        assert!(meta.has_pinned());
+        assert!(!unsafe { (*dead).is_pinned() });
+
+        assert!(Header::unpin(live));
+        meta.unpin();
+        assert!(!meta.has_pinned());
+    }
+
+    #[test]
+    fn evacuate_flag_survives_free_unmarked() {
+        let block = Block::default().unwrap();
+        let meta = BlockMeta::new(block.as_ptr());
+
+        meta.set_line(0, Mark::Red);
+        meta.set_line(1, Mark::Green);
+        meta.set_evacuating(true);
+        meta.free_unmarked(Mark::Red);
+
+        assert!(meta.is_evacuating());
+        assert_eq!(meta.count_marked_lines(), 1);
+
+        meta.set_evacuating(false);
+        assert!(!meta.is_evacuating());
+    }
 }
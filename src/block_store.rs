@@ -1,135 +1,1634 @@
+use super::arena_config::ArenaConfig;
 use super::block::Block;
+use super::block_list::BlockList;
+use super::block_meta::BlockMeta;
 use super::bump_block::BumpBlock;
+use super::card::Card;
+use super::constants::{BLOCK_CAPACITY, BLOCK_SIZE};
+use super::error::AllocErrorKind;
 use super::header::Header;
 use super::header::Mark;
+use super::refresh::{RefreshBudget, RefreshProgress};
+use super::region::Region;
+use super::reservation::Reservation;
+use super::size_class::SizeClass;
+use super::sweeper::SweepSignal;
 use std::alloc::Layout;
-use std::sync::atomic::{AtomicUsize, Ordering};
-use std::sync::Mutex;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::thread;
+use std::time::Instant;
+
+enum BlockState {
+    Free,
+    Recycle,
+    Rest,
+}
+
+struct Finalizer {
+    header: usize,
+    finalizer: fn(*mut u8),
+}
+
+struct WeakEntry {
+    slot: Weak<AtomicUsize>,
+    // distance from the object back to its header
+    header_offset: usize,
+}
 
 pub struct BlockStore {
+    // the number of blocks handed out of the store, free blocks are not counted
     block_count: AtomicUsize,
-    free: Mutex<Vec<BumpBlock>>,
-    recycle: Mutex<Vec<BumpBlock>>,
-    rest: Mutex<Vec<BumpBlock>>,
+    large_space: AtomicUsize,
+    hard_limit: AtomicUsize,
+    soft_limit: AtomicUsize,
+    // set when an allocation takes the heap over the soft limit
+    soft_limit_hit: AtomicBool,
+    // cleared once the limit is hit, until the next refresh
+    soft_limit_armed: AtomicBool,
+    config: ArenaConfig,
+    // taken from and pushed to on every allocator's slow path, so these are
+    // lock-free
+    free: BlockList<BumpBlock>,
+    recycle: BlockList<BumpBlock>,
+    // recycled blocks with little enough live in them to be evacuated, they
+    // are handed out once the recycle list is empty
+    sparse: BlockList<BumpBlock>,
+    rest: BlockList<BumpBlock>,
+    // held shared by take_free, a refresh holds it exclusively while it walks
+    // and trims the free list
+    free_trim: RwLock<()>,
     large: Mutex<Vec<Block>>,
+    // start address to size of every large object in large or unswept_large,
+    // so pointers into them are found without walking either list
+    large_index: RwLock<BTreeMap<usize, usize>>,
+    // dead large objects kept around to be reused
+    large_free: Mutex<Vec<Block>>,
+    evacuating: Mutex<Vec<BumpBlock>>,
+    // blocks waiting to be lazily swept with the sweep mark, and how many
+    unswept: BlockList<BumpBlock>,
+    unswept_count: AtomicUsize,
+    sweep_mark: AtomicU8,
+    // large objects waiting on an incremental refresh, and whether one is running
+    unswept_large: Mutex<Vec<Block>>,
+    stepping: AtomicBool,
+    // wakes the background sweeper, if there is one
+    sweep_signal: Option<Arc<SweepSignal>>,
+    regions: Mutex<Vec<Region>>,
+    // when set every region is committed out of this address range
+    reservation: Option<Reservation>,
+    weaks: Mutex<Vec<WeakEntry>>,
+    // set while a concurrent marker is running
+    marking: AtomicBool,
+    // overwritten references flushed by allocators, and their buffers
+    satb_queue: Mutex<Vec<usize>>,
+    satb_buffers: Mutex<Vec<Weak<Mutex<Vec<usize>>>>>,
+    // the remembered set, blocks with dirty cards and logged large objects
+    dirty_blocks: Mutex<Vec<usize>>,
+    dirty_large: Mutex<Vec<usize>>,
+    // objects to finalize once they are found unmarked, oldest first
+    finalizers: Mutex<Vec<Finalizer>>,
+    // objects found dead whose finalizers haven't run yet, the oldest last,
+    // and whether there are any. Unswept blocks are left alone until they ran.
+    dead_finalizers: Mutex<Vec<Finalizer>>,
+    finalizing: AtomicBool,
+    // where the blocks of every live AllocHead are, and the refresh epoch
+    // they check on their slow path
+    heads: Mutex<Vec<Weak<Mutex<Vec<usize>>>>>,
+    epoch: AtomicUsize,
+    refresh_lock: Mutex<()>,
 }
 
 impl BlockStore {
     pub fn new() -> Self {
-        Self {
+        Self::with_config(ArenaConfig::default())
+    }
+
+    pub fn with_config(config: ArenaConfig) -> Self {
+        let store = Self {
             block_count: AtomicUsize::new(0),
-            free: Mutex::new(vec![]),
-            recycle: Mutex::new(vec![]),
-            rest: Mutex::new(vec![]),
+            large_space: AtomicUsize::new(0),
+            hard_limit: AtomicUsize::new(config.get_max_heap_size()),
+            soft_limit: AtomicUsize::new(usize::MAX),
+            soft_limit_hit: AtomicBool::new(false),
+            soft_limit_armed: AtomicBool::new(true),
+            config: config.clone(),
+            free: BlockList::new(),
+            recycle: BlockList::new(),
+            sparse: BlockList::new(),
+            rest: BlockList::new(),
+            free_trim: RwLock::new(()),
             large: Mutex::new(vec![]),
+            large_index: RwLock::new(BTreeMap::new()),
+            large_free: Mutex::new(vec![]),
+            evacuating: Mutex::new(vec![]),
+            unswept: BlockList::new(),
+            unswept_count: AtomicUsize::new(0),
+            sweep_mark: AtomicU8::new(Mark::New as u8),
+            unswept_large: Mutex::new(vec![]),
+            stepping: AtomicBool::new(false),
+            sweep_signal: config
+                .get_background_sweep()
+                .then(|| Arc::new(SweepSignal::default())),
+            regions: Mutex::new(vec![]),
+            reservation: Self::reserve(&config),
+            weaks: Mutex::new(vec![]),
+            marking: AtomicBool::new(false),
+            satb_queue: Mutex::new(vec![]),
+            satb_buffers: Mutex::new(vec![]),
+            dirty_blocks: Mutex::new(vec![]),
+            dirty_large: Mutex::new(vec![]),
+            finalizers: Mutex::new(vec![]),
+            dead_finalizers: Mutex::new(vec![]),
+            finalizing: AtomicBool::new(false),
+            heads: Mutex::new(vec![]),
+            epoch: AtomicUsize::new(0),
+            refresh_lock: Mutex::new(()),
+        };
+
+        store.prewarm();
+        store
+    }
+
+    // Reserving is best effort, without a reservation regions come from the
+    // system allocator.
+    fn reserve(config: &ArenaConfig) -> Option<Reservation> {
+        match config.get_reserved_bytes() {
+            0 => None,
+            bytes => Reservation::new(bytes).ok(),
+        }
+    }
+
+    fn prewarm(&self) {
+        for _ in 0..self.config.get_initial_blocks() {
+            match self.new_block() {
+                Ok(block) => self.free.push(block),
+                Err(_) => break,
+            }
+        }
+    }
+
+    pub fn push_free(&self, mut block: BumpBlock) {
+        // regions from the system allocator aren't ours to hand back
+        if self.config.get_decommit_free_blocks() && self.reservation.is_some() {
+            block.decommit();
+        }
+
+        self.block_count.fetch_sub(1, Ordering::SeqCst);
+        self.free.push(block);
+    }
+
+    pub fn push_weak(&self, object: *const u8, header_offset: usize) -> Arc<AtomicUsize> {
+        let slot = Arc::new(AtomicUsize::new(object as usize));
+
+        self.weaks.lock().unwrap().push(WeakEntry {
+            slot: Arc::downgrade(&slot),
+            header_offset,
+        });
+
+        slot
+    }
+
+    pub fn set_marking(&self, marking: bool) {
+        self.marking.store(marking, Ordering::SeqCst);
+    }
+
+    pub fn is_marking(&self) -> bool {
+        self.marking.load(Ordering::Acquire)
+    }
+
+    pub fn register_satb_buffer(&self, buffer: &Arc<Mutex<Vec<usize>>>) {
+        self.satb_buffers
+            .lock()
+            .unwrap()
+            .push(Arc::downgrade(buffer));
+    }
+
+    pub fn push_satb(&self, entries: &mut Vec<usize>) {
+        if !entries.is_empty() {
+            self.satb_queue.lock().unwrap().append(entries);
+        }
+    }
+
+    // Takes everything logged so far, including what is still sitting in the
+    // buffers of the allocators.
+    pub fn take_satb(&self) -> Vec<usize> {
+        let mut buffers = self.satb_buffers.lock().unwrap();
+        let mut entries = std::mem::take(&mut *self.satb_queue.lock().unwrap());
+
+        buffers.retain(|buffer| buffer.strong_count() > 0);
+
+        for buffer in buffers.iter().filter_map(|buffer| buffer.upgrade()) {
+            entries.append(&mut buffer.lock().unwrap());
+        }
+
+        entries
+    }
+
+    pub fn write_barrier(&self, header: *const Header) {
+        if unsafe { (*header).get_size_class() } == SizeClass::Large {
+            if !Header::set_logged(header, true) {
+                self.dirty_large.lock().unwrap().push(header as usize);
+            }
+        } else if BlockMeta::from_header(header).dirty_card(header) {
+            let block = header as usize - header as usize % BLOCK_SIZE;
+
+            self.dirty_blocks.lock().unwrap().push(block);
         }
     }
 
+    // Takes every dirty card, leaving the remembered set empty.
+    pub fn take_dirty_cards(&self) -> Vec<Card> {
+        let blocks = std::mem::take(&mut *self.dirty_blocks.lock().unwrap());
+        let large = std::mem::take(&mut *self.dirty_large.lock().unwrap());
+        let mut cards = vec![];
+
+        for block in blocks {
+            let block = block as *const u8;
+
+            for card in BlockMeta::from_block(block).take_dirty_cards() {
+                cards.push(Card::new(block, card));
+            }
+        }
+
+        for header in large {
+            let header = header as *const Header;
+
+            Header::set_logged(header, false);
+            cards.push(Card::large(header));
+        }
+
+        cards
+    }
+
+    pub fn push_finalizer(&self, header: *const Header, finalizer: fn(*mut u8)) {
+        self.finalizers.lock().unwrap().push(Finalizer {
+            header: header as usize,
+            finalizer,
+        });
+    }
+
+    pub fn register_head(&self, addresses: &Arc<Mutex<Vec<usize>>>) {
+        self.heads.lock().unwrap().push(Arc::downgrade(addresses));
+    }
+
+    fn live_heads(&self) -> Vec<Arc<Mutex<Vec<usize>>>> {
+        let mut heads = self.heads.lock().unwrap();
+
+        heads.retain(|head| head.strong_count() > 0);
+        heads.iter().filter_map(|head| head.upgrade()).collect()
+    }
+
+    pub fn get_epoch(&self) -> usize {
+        self.epoch.load(Ordering::Acquire)
+    }
+
     pub fn push_rest(&self, block: BumpBlock) {
-        self.rest.lock().unwrap().push(block);
+        self.rest.push(block);
     }
 
+    // Sparse blocks are kept apart from the others, see
+    // select_evacuation_candidates.
     pub fn push_recycle(&self, block: BumpBlock) {
-        self.recycle.lock().unwrap().push(block);
+        if block.is_evacuation_candidate() {
+            self.sparse.push(block);
+        } else {
+            self.recycle.push(block);
+        }
     }
 
-    pub fn get_head(&self) -> Result<BumpBlock, ()> {
-        if let Some(recycle_block) = self.recycle.lock().unwrap().pop() {
-            Ok(recycle_block)
-        } else if let Some(free_block) = self.free.lock().unwrap().pop() {
-            Ok(free_block)
-        } else {
-            self.new_block()
+    fn pop_recycle(&self) -> Option<BumpBlock> {
+        self.recycle.pop().or_else(|| self.sparse.pop())
+    }
+
+    pub fn push_rest_batch(&self, blocks: impl IntoIterator<Item = BumpBlock>) {
+        self.rest.push_chain(blocks);
+    }
+
+    pub fn get_head_stash_size(&self) -> usize {
+        self.config.get_head_stash_size()
+    }
+
+    // Takes up to count blocks to allocate into, recycled blocks first and
+    // then free ones, each list is taken from in a single exchange. A new
+    // block is only made when neither list has any.
+    pub fn get_heads(&self, count: usize) -> Result<Vec<BumpBlock>, AllocErrorKind> {
+        let mut blocks = self.recycle.pop_n(count);
+
+        blocks.append(&mut self.sparse.pop_n(count - blocks.len()));
+
+        while blocks.len() < count {
+            match self.take_unswept(true) {
+                Some(block) => blocks.push(block),
+                None => break,
+            }
+        }
+
+        if blocks.len() < count {
+            // a batch over the hard limit may still leave room for one block
+            let wanted = count - blocks.len();
+
+            match self
+                .take_free_batch(wanted)
+                .or_else(|_| self.take_free_batch(1))
+            {
+                Ok(mut free) => blocks.append(&mut free),
+                Err(kind) if blocks.is_empty() => return Err(kind),
+                Err(_) => {}
+            }
+        }
+
+        if blocks.is_empty() {
+            blocks.push(self.get_overflow()?);
         }
+
+        Ok(blocks)
     }
 
-    pub fn get_overflow(&self) -> Result<BumpBlock, ()> {
-        if let Some(free_block) = self.free.lock().unwrap().pop() {
-            Ok(free_block)
-        } else {
-            self.new_block()
+    pub fn get_head(&self) -> Result<BumpBlock, AllocErrorKind> {
+        let recycle_block = self.pop_recycle();
+
+        match recycle_block.or_else(|| self.take_unswept(true)) {
+            Some(block) => Ok(block),
+            None => self.get_overflow(),
+        }
+    }
+
+    pub fn get_overflow(&self) -> Result<BumpBlock, AllocErrorKind> {
+        // unswept blocks are still counted as used, so no limit applies to them
+        if let Some(block) = self.take_unswept(false) {
+            return Ok(block);
+        }
+
+        if let Some(block) = self.take_free()? {
+            return Ok(block);
+        }
+
+        self.reserve_space(&self.block_count, 1)?;
+        self.new_block().inspect_err(|_| {
+            self.block_count.fetch_sub(1, Ordering::SeqCst);
+        })
+    }
+
+    fn take_free(&self) -> Result<Option<BumpBlock>, AllocErrorKind> {
+        Ok(self.take_free_batch(1)?.pop())
+    }
+
+    // The blocks are counted against the limits all at once, if they don't
+    // fit every one of them goes back.
+    fn take_free_batch(&self, count: usize) -> Result<Vec<BumpBlock>, AllocErrorKind> {
+        let _trim = self.free_trim.read().unwrap();
+        let mut blocks = self.free.pop_n(count);
+
+        if blocks.is_empty() {
+            return Ok(blocks);
+        }
+
+        if let Err(kind) = self.reserve_space(&self.block_count, blocks.len()) {
+            self.free.push_chain(blocks.into_iter().rev());
+            return Err(kind);
+        }
+
+        for block in blocks.iter_mut() {
+            block.recommit();
+            block.reset();
+        }
+
+        Ok(blocks)
+    }
+
+    fn new_block(&self) -> Result<BumpBlock, AllocErrorKind> {
+        let mut regions = self.regions.lock().unwrap();
+        let block_ptr = match regions.last_mut().and_then(|region| region.carve_block()) {
+            Some(block_ptr) => block_ptr,
+            None => {
+                let mut region = self.new_region()?;
+                let block_ptr = region.carve_block().unwrap();
+
+                regions.push(region);
+                block_ptr
+            }
+        };
+
+        Ok(BumpBlock::new(block_ptr))
+    }
+
+    fn new_region(&self) -> Result<Region, AllocErrorKind> {
+        match &self.reservation {
+            Some(reservation) => reservation.commit_region(),
+            None => Region::default(),
         }
     }
 
-    fn new_block(&self) -> Result<BumpBlock, ()> {
-        self.block_count.fetch_add(1, Ordering::SeqCst);
-        BumpBlock::new()
+    // Whether ptr points into memory owned by the store, this says nothing
+    // about whether an object lives there. With a reservation blocks are found
+    // by a range check, large objects are always searched for.
+    pub fn contains(&self, ptr: *const u8) -> bool {
+        self.owns_block(ptr) || self.find_large(ptr).is_some()
+    }
+
+    // Uncarved blocks of a reserved region are still zeroed, so only the
+    // regions of the system allocator need to check how much has been carved.
+    fn owns_block(&self, ptr: *const u8) -> bool {
+        match &self.reservation {
+            Some(reservation) => reservation.contains(ptr),
+            None => {
+                let base = Region::base_of(ptr);
+                let offset = ptr as usize - base;
+
+                self.regions.lock().unwrap().iter().any(|region| {
+                    region.as_ptr() as usize == base && offset < region.carved() * BLOCK_SIZE
+                })
+            }
+        }
+    }
+
+    // Only the last object starting at or below ptr can hold it.
+    fn find_large(&self, ptr: *const u8) -> Option<*const Header> {
+        let addr = ptr as usize;
+        let index = self.large_index.read().unwrap();
+        let (start, size) = index.range(..=addr).next_back()?;
+
+        (addr < start + size).then_some(*start as *const Header)
+    }
+
+    // Resolves a pointer anywhere inside an object to the object's header.
+    // Dead objects sharing a line with live ones may still be found, which
+    // only makes a conservative scan retain a little more than it needs to.
+    pub fn find_object(&self, ptr: *const u8) -> Option<*const Header> {
+        let header = match self.find_large(ptr) {
+            Some(header) => header,
+            None => self.find_in_block(ptr)?,
+        };
+
+        let start = Header::object_start(header) as usize;
+        let end = header as usize + unsafe { (*header).get_size() } as usize;
+
+        (start <= ptr as usize && (ptr as usize) < end).then_some(header)
+    }
+
+    // Walks every object the store holds, dead objects that haven't been swept
+    // yet included. No allocator should be allocating while this runs, the
+    // lists are walked in place, and the callback must not refresh the store.
+    pub fn for_each_object(&self, f: &mut dyn FnMut(*const Header)) {
+        let _refresh = self.refresh_lock.lock().unwrap();
+        let mut blocks: Vec<*const u8> = vec![];
+
+        for head in self.live_heads() {
+            blocks.extend(head.lock().unwrap().iter().map(|addr| *addr as *const u8));
+        }
+
+        for list in [&self.recycle, &self.sparse, &self.rest, &self.unswept] {
+            list.for_each(|block| blocks.push(block.as_ptr()));
+        }
+
+        let evacuating = self.evacuating.lock().unwrap();
+        blocks.extend(evacuating.iter().map(|block| block.as_ptr()));
+        drop(evacuating);
+
+        for block in blocks {
+            for offset in BlockMeta::from_block(block).object_starts(0..BLOCK_CAPACITY) {
+                f(unsafe { block.add(offset) } as *const Header);
+            }
+        }
+
+        let mut large: Vec<*const u8> = vec![];
+
+        for list in [&self.large, &self.unswept_large] {
+            large.extend(list.lock().unwrap().iter().map(|block| block.as_ptr()));
+        }
+
+        for block in large {
+            f(block as *const Header);
+        }
+    }
+
+    fn find_in_block(&self, ptr: *const u8) -> Option<*const Header> {
+        let offset = ptr as usize % BLOCK_SIZE;
+
+        if offset >= BLOCK_CAPACITY || !self.owns_block(ptr) {
+            return None;
+        }
+
+        let block = unsafe { ptr.sub(offset) };
+        let start = BlockMeta::from_block(block).find_object_start(offset)?;
+
+        Some(unsafe { block.add(start) } as *const Header)
+    }
+
+    // The index of the block holding ptr within the reservation.
+    pub fn block_index(&self, ptr: *const u8) -> Option<usize> {
+        self.reservation.as_ref()?.block_index(ptr)
     }
 
     pub fn block_count(&self) -> usize {
         self.block_count.load(Ordering::Relaxed)
     }
 
+    pub fn region_count(&self) -> usize {
+        self.regions.lock().unwrap().len()
+    }
+
     pub fn count_large_space(&self) -> usize {
-        self.large
-            .lock()
-            .unwrap()
-            .iter()
-            .fold(0, |sum, block| sum + block.get_size())
+        self.large_space.load(Ordering::Relaxed)
+    }
+
+    pub fn get_size(&self) -> usize {
+        self.block_count() * BLOCK_SIZE + self.count_large_space()
+    }
+
+    pub fn set_hard_limit(&self, bytes: usize) {
+        self.hard_limit.store(bytes, Ordering::SeqCst);
+    }
+
+    pub fn set_soft_limit(&self, bytes: usize) {
+        self.soft_limit.store(bytes, Ordering::SeqCst);
+        self.soft_limit_armed.store(true, Ordering::Release);
+    }
+
+    // A hit is held back while a refresh is running, an allocation from a
+    // finalizer would otherwise run the callback inside the refresh.
+    pub fn take_soft_limit_hit(&self) -> bool {
+        self.soft_limit_hit.load(Ordering::Relaxed)
+            && self.refresh_lock.try_lock().is_ok()
+            && self.soft_limit_hit.swap(false, Ordering::AcqRel)
+    }
+
+    // The space is counted before the limits are checked, so allocators racing
+    // each other see one another's space and can't overshoot the hard limit
+    // together. Nothing stays counted if the hard limit is hit.
+    fn reserve_space(&self, counter: &AtomicUsize, amount: usize) -> Result<(), AllocErrorKind> {
+        counter.fetch_add(amount, Ordering::SeqCst);
+
+        let result = self.check_heap_limit();
+
+        if result.is_err() {
+            counter.fetch_sub(amount, Ordering::SeqCst);
+        }
+
+        result
     }
 
-    pub fn create_large(&self, layout: Layout) -> Result<*const u8, ()> {
-        let block = Block::new(layout)?;
+    fn check_heap_limit(&self) -> Result<(), AllocErrorKind> {
+        let new_size = self.block_count.load(Ordering::SeqCst) * BLOCK_SIZE
+            + self.large_space.load(Ordering::SeqCst);
+
+        if new_size > self.hard_limit.load(Ordering::Relaxed) {
+            return Err(AllocErrorKind::HeapLimit);
+        }
+
+        // Hit at most once per refresh, the heap may still be over the limit
+        // after a refresh in which case the next growth hits it again.
+        let soft_limit = self.soft_limit.load(Ordering::Relaxed);
+        if soft_limit < new_size
+            && self.soft_limit_armed.load(Ordering::Relaxed)
+            && self.soft_limit_armed.swap(false, Ordering::AcqRel)
+        {
+            self.soft_limit_hit.store(true, Ordering::Release);
+        }
+
+        Ok(())
+    }
+
+    // init is run before the object is pushed, so a refresh never sees it
+    // without a header.
+    pub fn create_large<F>(&self, layout: Layout, init: F) -> Result<*const u8, AllocErrorKind>
+    where
+        F: FnOnce(*const u8),
+    {
+        let block = match self.take_large_free(layout) {
+            Some(block) => block,
+            None => {
+                self.reserve_space(&self.large_space, layout.size())?;
+                Block::new(layout).inspect_err(|_| {
+                    self.large_space.fetch_sub(layout.size(), Ordering::SeqCst);
+                })?
+            }
+        };
         let ptr = block.as_ptr();
 
+        init(ptr);
+        self.large_index
+            .write()
+            .unwrap()
+            .insert(ptr as usize, block.get_size());
         self.large.lock().unwrap().push(block);
         Ok(ptr)
     }
 
+    // picks the smallest retained large block the layout fits in
+    fn take_large_free(&self, layout: Layout) -> Option<Block> {
+        let mut large_free = self.large_free.lock().unwrap();
+        let index = large_free
+            .iter()
+            .enumerate()
+            .filter(|(_, block)| block.fits(layout))
+            .min_by_key(|(_, block)| block.get_size())
+            .map(|(index, _)| index)?;
+
+        if self
+            .reserve_space(&self.large_space, large_free[index].get_size())
+            .is_err()
+        {
+            return None;
+        }
+
+        Some(large_free.swap_remove(index))
+    }
+
+    // Sparse recycled blocks go to a list of their own as they're recycled,
+    // selecting them takes every one of them out of allocation so that nothing
+    // new gets allocated into them. Any object found in them during the next
+    // mark phase can then be evacuated, leaving the block free at the next refresh.
+    pub fn select_evacuation_candidates(&self) -> usize {
+        let candidates = self.sparse.take_all();
+        let count = candidates.len();
+        let mut evacuating = self.evacuating.lock().unwrap();
+
+        for block in candidates {
+            block.set_evacuating(true);
+            evacuating.push(block);
+        }
+
+        count
+    }
+
+    // AllocHeads keep the blocks they allocate into to themselves, so a
+    // refresh never waits on them and only sweeps the blocks the store holds.
+    // It moves the store on to a new epoch, which every head picks up at its
+    // next slow path by handing back the blocks it held and taking freshly
+    // swept ones. What was handed back is swept by the following refresh.
+    // Refreshing with Mark::New is a minor refresh, see Mark::survives.
     pub fn refresh(&self, mark: Mark) {
-        let mut free = self.free.lock().unwrap();
-        let mut rest = self.rest.lock().unwrap();
-        let mut large = self.large.lock().unwrap();
-        let mut recycle = self.recycle.lock().unwrap();
-        let mut new_rest = vec![];
-        let mut new_recycle = vec![];
-        let mut new_large = vec![];
+        let _refresh = self.refresh_lock.lock().unwrap();
 
-        while let Some(mut block) = recycle.pop() {
-            block.reset_hole(mark);
+        self.epoch.fetch_add(1, Ordering::AcqRel);
 
-            if block.is_marked(mark) {
-                new_recycle.push(block);
-            } else {
-                free.push(block);
+        // an incremental refresh still in progress is finished by this one
+        self.stepping.store(false, Ordering::Release);
+        self.large
+            .lock()
+            .unwrap()
+            .append(&mut self.unswept_large.lock().unwrap());
+
+        self.prepare_sweep(mark);
+
+        while self.run_finalizer() {}
+
+        if let Some(signal) = &self.sweep_signal {
+            self.queue_refresh(mark);
+            signal.notify();
+        } else if self.config.get_lazy_sweep() {
+            self.defer_sweep(mark);
+        } else {
+            self.sweep(mark);
+        }
+    }
+
+    // The first step of an incremental refresh starts a new epoch as refresh
+    // does and queues up every block to be swept, each step after that
+    // works until the budget is spent. The finalizers of dead objects run
+    // first, then the blocks are swept and last the emptied regions are
+    // released, one finalizer, block or region at a time. Allocators keep
+    // going between steps, once the finalizers have run any unswept block
+    // they take is swept on the spot as with lazy sweeping.
+    pub fn refresh_step(&self, mark: Mark, budget: RefreshBudget) -> RefreshProgress {
+        let _refresh = self.refresh_lock.lock().unwrap();
+
+        if !self.stepping.load(Ordering::Acquire) {
+            self.epoch.fetch_add(1, Ordering::AcqRel);
+            self.prepare_sweep(mark);
+            self.queue_refresh(mark);
+        }
+
+        self.step(budget)
+    }
+
+    // Continues a refresh queued up by refresh_step or a background refresh,
+    // without starting a new one.
+    pub fn sweep_queued(&self, budget: RefreshBudget) -> RefreshProgress {
+        let _refresh = self.refresh_lock.lock().unwrap();
+
+        if !self.stepping.load(Ordering::Acquire) {
+            return RefreshProgress::Done;
+        }
+
+        self.step(budget)
+    }
+
+    pub fn get_sweep_signal(&self) -> Option<Arc<SweepSignal>> {
+        self.sweep_signal.clone()
+    }
+
+    pub fn is_sweeping(&self) -> bool {
+        self.stepping.load(Ordering::Acquire)
+    }
+
+    fn queue_refresh(&self, mark: Mark) {
+        self.queue_blocks(mark, false);
+        self.unswept_large
+            .lock()
+            .unwrap()
+            .append(&mut self.large.lock().unwrap());
+        self.stepping.store(true, Ordering::Release);
+    }
+
+    // Must be called with the refresh lock held.
+    fn step(&self, budget: RefreshBudget) -> RefreshProgress {
+        let start = Instant::now();
+        let mut swept = 0;
+
+        while !budget.is_spent(start, swept) && (self.run_finalizer() || self.sweep_step()) {
+            swept += 1;
+        }
+
+        let remaining = self.dead_finalizers.lock().unwrap().len()
+            + self.unswept_count.load(Ordering::Acquire)
+            + self.unswept_large.lock().unwrap().len();
+
+        if remaining != 0 {
+            return RefreshProgress::InProgress(remaining);
+        }
+
+        let remaining = self.release_free_regions(|| {
+            let spent = budget.is_spent(start, swept);
+
+            swept += 1;
+            !spent
+        });
+
+        if remaining != 0 {
+            return RefreshProgress::InProgress(remaining);
+        }
+
+        self.stepping.store(false, Ordering::Release);
+
+        RefreshProgress::Done
+    }
+
+    // Sweeps a single unswept block or large object, false if none are left.
+    fn sweep_step(&self) -> bool {
+        let block = self.unswept.pop();
+        let mark = Mark::from(self.sweep_mark.load(Ordering::Acquire));
+
+        if let Some(mut block) = block {
+            self.unswept_count.fetch_sub(1, Ordering::AcqRel);
+
+            match Self::sweep_block(&mut block, mark) {
+                BlockState::Free => self.push_free(block),
+                BlockState::Recycle => self.push_recycle(block),
+                BlockState::Rest => self.push_rest(block),
+            }
+
+            return true;
+        }
+
+        let block = self.unswept_large.lock().unwrap().pop();
+
+        match block {
+            Some(block) => {
+                if let Some(block) = self.sweep_large_block(block, mark) {
+                    self.large.lock().unwrap().push(block);
+                }
+
+                true
             }
+            None => false,
         }
+    }
+
+    fn prepare_sweep(&self, mark: Mark) {
+        self.soft_limit_armed.store(true, Ordering::Release);
+        // weak handles are cleared before any finalizer sees its object
+        self.clear_weaks(mark);
+        self.queue_finalizers(mark);
+        // everything that survives a refresh is old, so nothing old can point
+        // at a New object afterwards
+        self.take_dirty_cards();
+    }
+
+    // Entries are dropped once every handle to them is gone or they're cleared.
+    fn clear_weaks(&self, mark: Mark) {
+        self.weaks.lock().unwrap().retain(|entry| {
+            let Some(slot) = entry.slot.upgrade() else {
+                return false;
+            };
 
-        while let Some(mut block) = rest.pop() {
-            block.reset_hole(mark);
+            let object = slot.load(Ordering::Acquire) as *const u8;
+            let header = unsafe { object.sub(entry.header_offset) } as *const Header;
 
-            if block.is_marked(mark) {
-                if block.current_hole_size() != 0 {
-                    new_recycle.push(block);
-                } else {
-                    new_rest.push(block);
+            let (object, header) = match Header::get_forward(header) {
+                Some(forward) => {
+                    let header = unsafe { forward.sub(entry.header_offset) } as *const Header;
+
+                    (forward, header)
                 }
+                None => (object, header),
+            };
+
+            if Header::get_mark(header).survives(mark) {
+                slot.store(object as usize, Ordering::Release);
+                true
+            } else {
+                slot.store(0, Ordering::Release);
+                false
+            }
+        });
+    }
+
+    // Runs before anything is queued to be swept, so every unmarked object is
+    // still intact. Evacuated objects are followed to their new location.
+    fn queue_finalizers(&self, mark: Mark) {
+        let mut finalizers = self.finalizers.lock().unwrap();
+        let mut dead = vec![];
+
+        finalizers.retain_mut(|entry| {
+            let mut header = entry.header as *const Header;
+
+            if let Some(forward) = Header::get_forward(header) {
+                let align = unsafe { (*header).get_align() };
+
+                header = unsafe { forward.sub(Header::object_offset(align)) } as *const Header;
+                entry.header = header as usize;
+            }
+
+            if Header::get_mark(header).survives(mark) {
+                true
+            } else {
+                dead.push(Finalizer {
+                    header: entry.header,
+                    finalizer: entry.finalizer,
+                });
+                false
+            }
+        });
+
+        drop(finalizers);
+
+        if dead.is_empty() {
+            return;
+        }
+
+        // whatever an unfinished refresh left behind is older and runs first
+        let mut queued = self.dead_finalizers.lock().unwrap();
+
+        dead.reverse();
+        dead.append(&mut queued);
+        *queued = dead;
+        self.finalizing.store(true, Ordering::Release);
+    }
+
+    // Runs the oldest finalizer still waiting, false if none are. It runs
+    // with no lock but the refresh lock held, so finalizers are free to
+    // allocate.
+    fn run_finalizer(&self) -> bool {
+        let Some(entry) = self.dead_finalizers.lock().unwrap().pop() else {
+            return false;
+        };
+        let object = Header::object_start(entry.header as *const Header);
+
+        (entry.finalizer)(object as *mut u8);
+
+        if self.dead_finalizers.lock().unwrap().is_empty() {
+            self.finalizing.store(false, Ordering::Release);
+        }
+
+        true
+    }
+
+    // Allocators keep taking and pushing blocks while this runs, anything
+    // pushed after the lists are taken waits for the next refresh.
+    fn sweep(&self, mark: Mark) {
+        let mut blocks = self.unswept.take_all();
+
+        self.unswept_count.fetch_sub(blocks.len(), Ordering::AcqRel);
+
+        // evacuated objects were not marked in their old location, so these
+        // blocks can be swept just like any other block
+        for block in self.evacuating.lock().unwrap().drain(..) {
+            block.set_evacuating(false);
+            blocks.push(block);
+        }
+
+        blocks.extend(self.recycle.take_all());
+        blocks.extend(self.sparse.take_all());
+        blocks.extend(self.rest.take_all());
+
+        let states = self.sweep_blocks(&mut blocks, mark);
+
+        for (block, state) in blocks.into_iter().zip(states) {
+            match state {
+                BlockState::Free => self.push_free(block),
+                BlockState::Recycle => self.push_recycle(block),
+                BlockState::Rest => self.rest.push(block),
+            }
+        }
+
+        self.sweep_large(mark);
+        self.release_free_blocks();
+    }
+
+    fn release_free_blocks(&self) {
+        self.release_free_regions(|| true);
+    }
+
+    // Releases the regions whose blocks are all free for as long as release
+    // agrees to each one, returns how many were left. Allocators wait in
+    // take_free while the free list is walked, it is only taken apart when a
+    // region was actually released.
+    fn release_free_regions(&self, mut release: impl FnMut() -> bool) -> usize {
+        let _trim = self.free_trim.write().unwrap();
+        let mut regions = self.regions.lock().unwrap();
+        let releasable = self.releasable_regions(&regions);
+        let mut released = HashSet::new();
+
+        if releasable.is_empty() {
+            return 0;
+        }
+
+        regions.retain(|region| {
+            let base = region.as_ptr() as usize;
+
+            if !releasable.contains(&base) || released.len() == releasable.len() || !release() {
+                return true;
+            }
+
+            if let Some(reservation) = &self.reservation {
+                reservation.release_region(region);
+            }
+
+            released.insert(base);
+            false
+        });
+
+        if !released.is_empty() {
+            let free = self.free.take_all();
+
+            self.free.push_chain(
+                free.into_iter()
+                    .rev()
+                    .filter(|block| !released.contains(&block.region_base())),
+            );
+        }
+
+        releasable.len() - released.len()
+    }
+
+    // Lazy sweeping only records the mark to sweep with, blocks are swept
+    // when an AllocHead takes them through get_head or get_overflow. Blocks
+    // with no survivors at all are told apart by their block mark alone and
+    // go to the free list right away, so the arena size drops and their
+    // regions can be released. Their lines aren't looked at until the block
+    // is taken again, see take_free.
+    fn defer_sweep(&self, mark: Mark) {
+        self.queue_blocks(mark, true);
+        self.sweep_large(mark);
+        self.release_free_blocks();
+    }
+
+    // Moves every block into the unswept list to be swept with the mark, or
+    // straight to the free list if free_dead is set and its block mark is dead.
+    fn queue_blocks(&self, mark: Mark, free_dead: bool) {
+        // stored before any block is queued, so a block is never swept with a
+        // mark older than the one it was queued with, see take_unswept
+        self.sweep_mark.store(mark as u8, Ordering::Release);
+
+        let mut blocks = vec![];
+
+        for block in self.evacuating.lock().unwrap().drain(..).rev() {
+            block.set_evacuating(false);
+            blocks.push(block);
+        }
+
+        blocks.extend(self.recycle.take_all().into_iter().rev());
+        blocks.extend(self.sparse.take_all().into_iter().rev());
+        blocks.extend(self.rest.take_all().into_iter().rev());
+
+        for block in blocks {
+            if free_dead && !block.is_marked(mark) {
+                self.push_free(block);
             } else {
-                free.push(block);
+                self.unswept_count.fetch_add(1, Ordering::AcqRel);
+                self.unswept.push(block);
+            }
+        }
+    }
+
+    // Sweeps unswept blocks until a free one is found, or one with a hole if
+    // take_recycle is set. The other swept blocks go to the recycle and rest lists.
+    fn take_unswept(&self, take_recycle: bool) -> Option<BumpBlock> {
+        loop {
+            let mut block = self.unswept.pop()?;
+            // loaded after the pop, a refresh may have queued the block since
+            let mark = Mark::from(self.sweep_mark.load(Ordering::Acquire));
+
+            // the objects of waiting finalizers have to stay intact
+            if self.finalizing.load(Ordering::Acquire) {
+                self.unswept.push(block);
+                return None;
             }
+
+            self.unswept_count.fetch_sub(1, Ordering::AcqRel);
+
+            match Self::sweep_block(&mut block, mark) {
+                BlockState::Free => return Some(block),
+                BlockState::Recycle if take_recycle => return Some(block),
+                BlockState::Recycle => self.push_recycle(block),
+                BlockState::Rest => self.push_rest(block),
+            }
+        }
+    }
+
+    // The blocks are split between the configured number of sweep threads.
+    fn sweep_blocks(&self, blocks: &mut [BumpBlock], mark: Mark) -> Vec<BlockState> {
+        let sweep = |chunk: &mut [BumpBlock]| -> Vec<BlockState> {
+            chunk
+                .iter_mut()
+                .map(|block| Self::sweep_block(block, mark))
+                .collect()
+        };
+
+        let Some(chunk_size) = self.sweep_chunk_size(blocks.len()) else {
+            return sweep(blocks);
+        };
+
+        thread::scope(|scope| {
+            let handles: Vec<_> = blocks
+                .chunks_mut(chunk_size)
+                .map(|chunk| scope.spawn(move || sweep(chunk)))
+                .collect();
+
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap())
+                .collect()
+        })
+    }
+
+    fn sweep_chunk_size(&self, len: usize) -> Option<usize> {
+        let threads = self.config.get_sweep_threads();
+
+        if threads <= 1 || len < threads {
+            None
+        } else {
+            Some(len.div_ceil(threads))
+        }
+    }
+
+    fn sweep_block(block: &mut BumpBlock, mark: Mark) -> BlockState {
+        block.reset_hole(mark);
+
+        if !block.is_marked(mark) {
+            BlockState::Free
+        } else if block.current_hole_size() != 0 {
+            BlockState::Recycle
+        } else {
+            BlockState::Rest
+        }
+    }
+
+    fn sweep_large(&self, mark: Mark) {
+        let mut large = self.large.lock().unwrap();
+        let sweep = |blocks: Vec<Block>| -> Vec<Block> {
+            blocks
+                .into_iter()
+                .filter_map(|block| self.sweep_large_block(block, mark))
+                .collect()
+        };
+
+        let Some(chunk_size) = self.sweep_chunk_size(large.len()) else {
+            *large = sweep(std::mem::take(&mut *large));
+            return;
+        };
+
+        let mut chunks = vec![];
+        while !large.is_empty() {
+            let at = large.len().saturating_sub(chunk_size);
+            chunks.push(large.split_off(at));
         }
 
-        while let Some(block) = large.pop() {
-            let header = block.as_ptr() as *const Header;
-            if Header::get_mark(header) == mark {
-                new_large.push(block);
+        thread::scope(|scope| {
+            let handles: Vec<_> = chunks
+                .into_iter()
+                .map(|chunk| scope.spawn(move || sweep(chunk)))
+                .collect();
+
+            for handle in handles {
+                large.extend(handle.join().unwrap());
             }
+        });
+    }
+
+    // Returns the block if its object survives.
+    fn sweep_large_block(&self, block: Block, mark: Mark) -> Option<Block> {
+        let header = block.as_ptr() as *const Header;
+
+        if Header::get_mark(header).survives(mark) {
+            return Some(block);
         }
 
-        *rest = new_rest;
-        *recycle = new_recycle;
-        *large = new_large;
+        self.large_index.write().unwrap().remove(&(header as usize));
+        self.large_space
+            .fetch_sub(block.get_size(), Ordering::Relaxed);
+        self.retain_large_free(block);
+        None
+    }
+
+    fn retain_large_free(&self, block: Block) {
+        let mut large_free = self.large_free.lock().unwrap();
+        let retained: usize = large_free.iter().map(|block| block.get_size()).sum();
 
-        // TODO: don't free blocks instead free regions
-        // if the ratio of free to used blocks is very high
-        // we will choose a page to compact
-        for _ in 0..10_000 {
-            if free.len() == 0 {
-                break;
+        if retained + block.get_size() <= self.config.get_large_bytes_retained() {
+            large_free.push(block);
+        }
+    }
+
+    // A region can only be given back once every block carved from it is free.
+    fn releasable_regions(&self, regions: &[Region]) -> HashSet<usize> {
+        let mut free_per_region = HashMap::<usize, usize>::new();
+        let mut free_left = 0;
+
+        self.free.for_each(|block| {
+            *free_per_region.entry(block.region_base()).or_insert(0) += 1;
+            free_left += 1;
+        });
+
+        let mut releasable = HashSet::new();
+
+        for region in regions.iter() {
+            let base = region.as_ptr() as usize;
+            let carved = region.carved();
+
+            if free_per_region.get(&base) == Some(&carved)
+                && free_left - carved >= self.config.get_free_blocks_retained()
+            {
+                free_left -= carved;
+                releasable.insert(base);
             }
+        }
+
+        releasable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::constants::{REGION_BLOCKS, REGION_SIZE};
+    use super::super::size_class::SizeClass;
+    use super::*;
+
+    fn mark_object(block: &mut BumpBlock, mark: Mark) {
+        let ptr = block.inner_alloc(Layout::new::<Header>()).unwrap() as *mut Header;
+
+        unsafe { std::ptr::write(ptr, Header::new(SizeClass::Small, 16, 8)) };
+        Header::set_mark(ptr, mark);
+    }
+
+    #[test]
+    fn blocks_share_a_region() {
+        let store = BlockStore::new();
+        let mut blocks = vec![];
+
+        for _ in 0..REGION_BLOCKS {
+            blocks.push(store.get_head().unwrap());
+        }
+
+        assert_eq!(store.region_count(), 1);
+        assert!(blocks
+            .iter()
+            .all(|block| block.region_base() == blocks[0].region_base()));
+
+        blocks.push(store.get_head().unwrap());
+        assert_eq!(store.region_count(), 2);
+    }
+
+    #[test]
+    fn release_free_region() {
+        let store = BlockStore::new();
 
-            self.block_count.fetch_sub(1, Ordering::Relaxed);
-            free.pop();
+        for _ in 0..(REGION_BLOCKS + 1) {
+            let block = store.get_head().unwrap();
+            store.push_rest(block);
         }
+
+        assert_eq!(store.block_count(), REGION_BLOCKS + 1);
+        assert_eq!(store.region_count(), 2);
+
+        store.refresh(Mark::Red);
+
+        assert_eq!(store.block_count(), 0);
+        assert_eq!(store.region_count(), 0);
+    }
+
+    #[test]
+    fn keep_partially_used_region() {
+        let store = BlockStore::new();
+        let held = store.get_head().unwrap();
+        let block = store.get_head().unwrap();
+
+        store.push_rest(block);
+        store.refresh(Mark::Red);
+
+        assert_eq!(store.block_count(), 1);
+        assert_eq!(store.region_count(), 1);
+
+        let reused = store.get_head().unwrap();
+        assert_eq!(reused.region_base(), held.region_base());
+        assert_eq!(store.block_count(), 2);
+    }
+
+    #[test]
+    fn lazy_sweep() {
+        let store = BlockStore::with_config(ArenaConfig::new().lazy_sweep(true));
+        let mut blocks = vec![];
+
+        for _ in 0..4 {
+            blocks.push(store.get_head().unwrap());
+        }
+
+        mark_object(&mut blocks[3], Mark::Red);
+
+        for block in blocks {
+            store.push_rest(block);
+        }
+
+        store.refresh(Mark::Red);
+
+        // dead blocks are freed right away, the marked one waits to be swept
+        assert_eq!(store.get_size(), BLOCK_SIZE);
+        assert_eq!(store.free.len(), 3);
+        assert_eq!(store.unswept.len(), 1);
+
+        // the marked block is swept while looking for a free block
+        store.get_overflow().unwrap();
+        assert_eq!(store.block_count(), 2);
+        // a single object leaves it sparse enough to be evacuated
+        assert_eq!(store.sparse.len(), 1);
+        assert!(store.unswept.is_empty());
+
+        let head = store.get_head().unwrap();
+        assert!(head.is_marked(Mark::Red));
+    }
+
+    #[test]
+    fn lazy_dead_blocks_reset_when_taken() {
+        let config = ArenaConfig::new().lazy_sweep(true).free_blocks_retained(1);
+        let store = BlockStore::with_config(config);
+        let mut block = store.get_head().unwrap();
+
+        for _ in 0..10 {
+            mark_object(&mut block, Mark::Blue);
+        }
+
+        store.push_rest(block);
+        store.refresh(Mark::Red);
+        assert_eq!(store.block_count(), 0);
+
+        // the block was freed without its lines being touched
+        let block = store.free.pop().unwrap();
+        assert!(BlockMeta::from_block(block.as_ptr()).count_marked_lines() > 0);
+        store.free.push(block);
+
+        let block = store.get_overflow().unwrap();
+        let meta = BlockMeta::from_block(block.as_ptr());
+
+        assert_eq!(meta.count_marked_lines(), 0);
+        assert_eq!(meta.object_starts(0..BLOCK_CAPACITY).count(), 0);
+        assert_eq!(block.current_hole_size(), BLOCK_CAPACITY);
+    }
+
+    #[test]
+    fn parallel_sweep() {
+        let store = BlockStore::with_config(ArenaConfig::new().sweep_threads(4));
+        let layout = Layout::from_size_align(BLOCK_SIZE * 2, 8).unwrap();
+        let mut blocks = vec![];
+
+        for _ in 0..(REGION_BLOCKS * 2) {
+            blocks.push(store.get_head().unwrap());
+        }
+
+        for (i, block) in blocks.iter_mut().enumerate() {
+            if i % 2 == 0 {
+                mark_object(block, Mark::Red);
+            }
+        }
+
+        for block in blocks {
+            store.push_rest(block);
+        }
+
+        for i in 0..10 {
+            let ptr = store.create_large(layout, |_| {}).unwrap() as *mut Header;
+            let mark = if i % 2 == 0 { Mark::Red } else { Mark::Green };
+
+            unsafe { std::ptr::write(ptr, Header::new(SizeClass::Large, 0, 8)) };
+            Header::set_mark(ptr, mark);
+        }
+
+        store.refresh(Mark::Red);
+
+        assert_eq!(store.block_count(), REGION_BLOCKS);
+        assert_eq!(store.sparse.len(), REGION_BLOCKS);
+        assert_eq!(store.free.len(), REGION_BLOCKS);
+        assert_eq!(store.large.lock().unwrap().len(), 5);
+        assert_eq!(store.count_large_space(), BLOCK_SIZE * 2 * 5);
+    }
+
+    #[test]
+    fn decommit_free_blocks() {
+        for (lazy, reserved) in [(false, false), (false, true), (true, true)] {
+            let config = ArenaConfig::new()
+                .decommit_free_blocks(true)
+                .free_blocks_retained(REGION_BLOCKS)
+                .reserved_bytes(if reserved { REGION_SIZE * 2 } else { 0 })
+                .lazy_sweep(lazy);
+            let store = BlockStore::with_config(config);
+            let layout = Layout::from_size_align(64, 8).unwrap();
+
+            let mut block = store.get_head().unwrap();
+            let ptr = block.inner_alloc(layout).unwrap() as *mut u8;
+            unsafe { std::ptr::write_bytes(ptr, 0xAB, layout.size()) };
+
+            store.push_rest(block);
+            store.refresh(Mark::Red);
+            assert_eq!(store.block_count(), 0);
+            assert_eq!(store.region_count(), 1);
+
+            let decommitted = reserved && cfg!(target_os = "linux");
+            assert_eq!(unsafe { *ptr } == 0, decommitted);
+
+            let mut block = store.get_head().unwrap();
+            assert_eq!(store.block_count(), 1);
+            assert!(block.inner_alloc(layout).is_some());
+        }
+    }
+
+    #[test]
+    fn contains_block_and_large_pointers() {
+        let layout = Layout::from_size_align(BLOCK_SIZE * 2, 8).unwrap();
+
+        for config in [
+            ArenaConfig::new(),
+            ArenaConfig::new().reserved_bytes(REGION_SIZE * 4),
+        ] {
+            let store = BlockStore::with_config(config);
+            let block = store.get_head().unwrap();
+            let large = store.create_large(layout, |_| {}).unwrap();
+
+            assert!(store.contains(block.as_ptr()));
+            assert!(store.contains(unsafe { block.as_ptr().add(BLOCK_SIZE - 1) }));
+            assert!(store.contains(large));
+            assert!(store.contains(unsafe { large.add(layout.size() - 1) }));
+            assert!(!store.contains(std::ptr::null()));
+            assert!(!store.contains(&layout as *const Layout as *const u8));
+        }
+    }
+
+    #[test]
+    fn dead_large_objects_leave_the_index() {
+        let layout = Layout::from_size_align(BLOCK_SIZE * 2, 8).unwrap();
+        let store = BlockStore::new();
+        let init = |ptr: *const u8| unsafe {
+            std::ptr::write(ptr as *mut Header, Header::new(SizeClass::Large, 0, 8))
+        };
+        let kept = store.create_large(layout, init).unwrap();
+        let dead = store.create_large(layout, init).unwrap();
+
+        Header::set_mark(kept as *const Header, Mark::Red);
+        store.refresh(Mark::Red);
+
+        assert!(store.contains(unsafe { kept.add(layout.size() - 1) }));
+        assert!(!store.contains(dead));
+        assert!(!store.contains(unsafe { dead.add(BLOCK_SIZE) }));
+        assert_eq!(store.large_index.read().unwrap().len(), 1);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reserved_block_index() {
+        let config = ArenaConfig::new().reserved_bytes(REGION_SIZE * 2);
+        let store = BlockStore::with_config(config);
+        let mut blocks = vec![];
+
+        for i in 0..(REGION_BLOCKS * 2) {
+            let block = store.get_head().unwrap();
+
+            assert_eq!(store.block_index(block.as_ptr()), Some(i));
+            blocks.push(block);
+        }
+
+        assert_eq!(store.get_head().err(), Some(AllocErrorKind::OutOfMemory));
+
+        for block in blocks {
+            store.push_rest(block);
+        }
+
+        store.refresh(Mark::Red);
+        assert_eq!(store.region_count(), 0);
+        assert!(store.get_head().is_ok());
+    }
+
+    #[test]
+    fn prewarm_free_blocks() {
+        let config = ArenaConfig::new()
+            .initial_blocks(REGION_BLOCKS * 2)
+            .free_blocks_retained(REGION_BLOCKS);
+        let store = BlockStore::with_config(config);
+
+        assert_eq!(store.block_count(), 0);
+        assert_eq!(store.region_count(), 2);
+
+        store.refresh(Mark::Red);
+        assert_eq!(store.region_count(), 1);
+
+        store.get_head().unwrap();
+        assert_eq!(store.block_count(), 1);
+        assert_eq!(store.region_count(), 1);
+    }
+
+    #[test]
+    fn max_heap_size() {
+        let config = ArenaConfig::new().max_heap_size(BLOCK_SIZE * 2);
+        let store = BlockStore::with_config(config);
+        let layout = Layout::from_size_align(BLOCK_SIZE, 8).unwrap();
+
+        store.get_head().unwrap();
+        store.get_overflow().unwrap();
+
+        assert_eq!(store.get_head().err(), Some(AllocErrorKind::HeapLimit));
+        assert_eq!(
+            store.create_large(layout, |_| {}).err(),
+            Some(AllocErrorKind::HeapLimit)
+        );
+    }
+
+    #[test]
+    fn max_heap_size_between_threads() {
+        let limit = 16;
+        let config = ArenaConfig::new().max_heap_size(BLOCK_SIZE * limit);
+        let store = BlockStore::with_config(config);
+        let layout = Layout::from_size_align(BLOCK_SIZE, 8).unwrap();
+
+        let taken: usize = std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|i| {
+                    let store = &store;
+
+                    scope.spawn(move || {
+                        let mut taken = 0;
+
+                        loop {
+                            let result = if i % 2 == 0 {
+                                store.get_overflow().map(|block| store.push_rest(block))
+                            } else {
+                                store.create_large(layout, |_| {}).map(|_| ())
+                            };
+
+                            if result.is_err() {
+                                return taken;
+                            }
+
+                            taken += 1;
+                        }
+                    })
+                })
+                .collect();
+
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+
+        assert!(taken <= limit);
+        assert!(store.get_size() <= BLOCK_SIZE * limit);
+    }
+
+    #[test]
+    fn reuse_retained_large() {
+        let config = ArenaConfig::new().large_bytes_retained(BLOCK_SIZE * 4);
+        let store = BlockStore::with_config(config);
+        let layout = Layout::from_size_align(BLOCK_SIZE * 2, 8).unwrap();
+        let smaller = Layout::from_size_align(BLOCK_SIZE, 8).unwrap();
+
+        let ptr = store.create_large(layout, |_| {}).unwrap();
+        unsafe { std::ptr::write(ptr as *mut Header, Header::new(SizeClass::Large, 0, 8)) };
+
+        store.refresh(Mark::Red);
+        assert_eq!(store.count_large_space(), 0);
+
+        assert_eq!(store.create_large(smaller, |_| {}).unwrap(), ptr);
+        assert_eq!(store.count_large_space(), BLOCK_SIZE * 2);
+    }
+
+    #[test]
+    fn refresh_in_steps() {
+        let store = BlockStore::new();
+        let layout = Layout::from_size_align(BLOCK_SIZE, 8).unwrap();
+        let mut blocks = vec![];
+
+        for _ in 0..4 {
+            blocks.push(store.get_head().unwrap());
+        }
+
+        mark_object(&mut blocks[3], Mark::Red);
+
+        for block in blocks {
+            store.push_rest(block);
+        }
+
+        let ptr = store.create_large(layout, |_| {}).unwrap();
+        unsafe { std::ptr::write(ptr as *mut Header, Header::new(SizeClass::Large, 0, 8)) };
+
+        // the first step queues everything up before sweeping
+        assert_eq!(
+            store.refresh_step(Mark::Red, RefreshBudget::Blocks(2)),
+            RefreshProgress::InProgress(3)
+        );
+        // the marked block was swept first and kept
+        assert_eq!(store.block_count(), 3);
+        assert!(store.contains(ptr));
+
+        assert_eq!(
+            store.refresh_step(Mark::Red, RefreshBudget::Blocks(2)),
+            RefreshProgress::InProgress(1)
+        );
+        assert_eq!(store.block_count(), 1);
+
+        assert!(store
+            .refresh_step(Mark::Red, RefreshBudget::Blocks(2))
+            .is_done());
+        assert_eq!(store.count_large_space(), 0);
+        assert_eq!(store.sparse.len(), 1);
+        assert_eq!(store.free.len(), 3);
+    }
+
+    static STEP_FINALIZED: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_finalized(_: *mut u8) {
+        STEP_FINALIZED.fetch_add(1, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn finalizers_run_in_steps() {
+        let store = BlockStore::new();
+        let mut block = store.get_head().unwrap();
+
+        for _ in 0..3 {
+            let ptr = block.inner_alloc(Layout::new::<Header>()).unwrap() as *mut Header;
+
+            unsafe { std::ptr::write(ptr, Header::new(SizeClass::Small, 16, 8)) };
+            store.push_finalizer(ptr, count_finalized);
+        }
+
+        store.push_rest(block);
+
+        // two of the finalizers fit in the first step, the block waits on the third
+        assert_eq!(
+            store.refresh_step(Mark::Red, RefreshBudget::Blocks(2)),
+            RefreshProgress::InProgress(2)
+        );
+        assert_eq!(STEP_FINALIZED.load(Ordering::SeqCst), 2);
+        assert!(store.take_unswept(true).is_none());
+
+        // the region is released by a step of its own
+        assert_eq!(
+            store.refresh_step(Mark::Red, RefreshBudget::Blocks(2)),
+            RefreshProgress::InProgress(1)
+        );
+        assert_eq!(STEP_FINALIZED.load(Ordering::SeqCst), 3);
+        assert_eq!(store.region_count(), 1);
+
+        assert!(store
+            .refresh_step(Mark::Red, RefreshBudget::Blocks(2))
+            .is_done());
+        assert_eq!(store.region_count(), 0);
+    }
+
+    #[test]
+    fn regions_released_in_steps() {
+        let store = BlockStore::new();
+
+        for _ in 0..(REGION_BLOCKS + 1) {
+            let block = store.get_head().unwrap();
+            store.push_rest(block);
+        }
+
+        assert_eq!(
+            store.refresh_step(Mark::Red, RefreshBudget::Blocks(REGION_BLOCKS + 1)),
+            RefreshProgress::InProgress(2)
+        );
+        assert_eq!(store.region_count(), 2);
+
+        assert_eq!(
+            store.refresh_step(Mark::Red, RefreshBudget::Blocks(1)),
+            RefreshProgress::InProgress(1)
+        );
+        assert_eq!(store.region_count(), 1);
+        assert_eq!(store.free.len(), 1);
+
+        assert!(store
+            .refresh_step(Mark::Red, RefreshBudget::Blocks(1))
+            .is_done());
+        assert_eq!(store.region_count(), 0);
+        assert_eq!(store.block_count(), 0);
     }
 }
@@ -1,34 +1,78 @@
-use super::block::Block;
 use super::block_meta::BlockMeta;
-use super::constants::{BLOCK_CAPACITY, SMALL_OBJECT_MIN};
+use super::constants::{BLOCK_CAPACITY, BLOCK_SIZE, EVACUATE_MAX_LIVE_LINES, SMALL_OBJECT_MIN};
 use super::header::Mark;
+use super::region::Region;
 use std::alloc::Layout;
+use std::ptr::NonNull;
 
+// A bump block does not own its memory, it is carved out of a Region
+// which is owned by the BlockStore.
 pub struct BumpBlock {
     cursor: usize,
     limit: usize,
-    block: Block,
+    block: NonNull<u8>,
     meta: BlockMeta,
+    // set once the block has been allocated into since it was last swept
+    touched: bool,
+    // set while the block's pages have been given back to the OS
+    decommitted: bool,
 }
 
+unsafe impl Send for BumpBlock {}
+
 impl BumpBlock {
-    pub fn new() -> Result<BumpBlock, ()> {
-        let inner_block = Block::default()?;
-        let block_ptr = inner_block.as_ptr();
-        let block = BumpBlock {
+    pub fn new(block_ptr: *const u8) -> BumpBlock {
+        debug_assert!((block_ptr as usize).is_multiple_of(BLOCK_SIZE));
+
+        BumpBlock {
             cursor: BLOCK_CAPACITY,
             limit: 0,
-            block: inner_block,
+            block: NonNull::new(block_ptr as *mut u8).unwrap(),
             meta: BlockMeta::new(block_ptr),
-        };
+            touched: false,
+            decommitted: false,
+        }
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.block.as_ptr()
+    }
+
+    pub fn region_base(&self) -> usize {
+        Region::base_of(self.as_ptr())
+    }
+
+    // Only free blocks may be decommitted, their line marks are all New
+    // which is also what the zeroed pages will read as.
+    pub fn decommit(&mut self) {
+        if !self.decommitted {
+            Region::decommit(self.as_ptr(), BLOCK_SIZE);
+            self.decommitted = true;
+        }
+    }
 
-        Ok(block)
+    // Only clears the flag, no syscall is needed. This relies on Linux mapping
+    // a fresh zeroed page in on the first touch after MADV_DONTNEED, on other
+    // systems decommit never gave the pages back in the first place.
+    pub fn recommit(&mut self) {
+        self.decommitted = false;
+    }
+
+    // Readies a free block to be allocated into. A block that went to the
+    // free list on its block mark alone still has the marks and object starts
+    // of the objects that died in it.
+    pub fn reset(&mut self) {
+        self.cursor = BLOCK_CAPACITY;
+        self.limit = 0;
+        self.touched = false;
+        self.meta.reset();
     }
 
     pub fn reset_hole(&mut self, mark: Mark) {
+        self.touched = false;
         self.meta.free_unmarked(mark);
 
-        if self.meta.get_block() != mark {
+        if !self.meta.get_block().survives(mark) {
             self.cursor = BLOCK_CAPACITY;
             self.limit = 0;
             return;
@@ -52,8 +96,10 @@ impl BumpBlock {
 
             if self.limit <= next_ptr {
                 self.cursor = next_ptr;
+                self.touched = true;
+                self.meta.set_object_start(self.cursor);
 
-                return Some(self.block.at_offset(self.cursor));
+                return Some(unsafe { self.block.as_ptr().add(self.cursor) });
             }
 
             if let Some((cursor, limit)) = self
@@ -68,23 +114,46 @@ impl BumpBlock {
         }
     }
 
+    // Whether nothing in the block is in use, as with a block fresh off the
+    // free list.
+    pub fn is_free(&self) -> bool {
+        !self.touched && self.current_hole_size() == BLOCK_CAPACITY
+    }
+
     pub fn current_hole_size(&self) -> usize {
         self.cursor - self.limit
     }
 
     pub fn is_marked(&self, mark: Mark) -> bool {
-        self.meta.get_block() == mark
+        self.meta.get_block().survives(mark)
+    }
+
+    // Only blocks that haven't been allocated into since they were swept have
+    // line marks that tell us how much of the block is live.
+    pub fn is_evacuation_candidate(&self) -> bool {
+        !self.touched
+            && !self.meta.has_pinned()
+            && self.meta.count_marked_lines() <= EVACUATE_MAX_LIVE_LINES
+    }
+
+    pub fn set_evacuating(&self, evacuating: bool) {
+        self.meta.set_evacuating(evacuating);
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::super::constants::LINE_COUNT;
+    use super::super::constants::{LINE_COUNT, LINE_SIZE};
     use super::*;
 
+    fn new_block(region: &mut Region) -> BumpBlock {
+        BumpBlock::new(region.carve_block().unwrap())
+    }
+
     #[test]
     fn test_empty_block() {
-        let mut b = BumpBlock::new().unwrap();
+        let mut region = Region::default().unwrap();
+        let mut b = new_block(&mut region);
 
         for i in 0..BLOCK_CAPACITY {
             let ptr = b.inner_alloc(Layout::new::<u8>()).unwrap();
@@ -99,7 +168,8 @@ mod tests {
 
     #[test]
     fn test_full_block() {
-        let mut b = BumpBlock::new().unwrap();
+        let mut region = Region::default().unwrap();
+        let mut b = new_block(&mut region);
 
         for i in 0..LINE_COUNT {
             b.meta.set_line(i, Mark::Red);
@@ -112,18 +182,21 @@ mod tests {
 
     #[test]
     fn test_half_block() {
-        let mut b = BumpBlock::new().unwrap();
+        let mut region = Region::default().unwrap();
+        let mut b = new_block(&mut region);
+
+        let half_lines = BLOCK_CAPACITY / LINE_SIZE / 2;
 
-        for i in ((LINE_COUNT - 2) / 2)..LINE_COUNT {
+        for i in half_lines..LINE_COUNT {
             b.meta.set_line(i, Mark::Red);
         }
 
         b.reset_hole(Mark::Red);
 
-        for i in 0..(BLOCK_CAPACITY / 2) {
+        for i in 0..(half_lines * LINE_SIZE) {
             let ptr = b.inner_alloc(Layout::new::<u8>()).unwrap();
 
-            let offset = (BLOCK_CAPACITY / 2) - (i + 1);
+            let offset = (half_lines * LINE_SIZE) - (i + 1);
             assert_eq!(b.cursor, offset);
             assert!(ptr as usize == b.block.as_ptr() as usize + offset);
         }
@@ -135,7 +208,8 @@ mod tests {
 
     #[test]
     fn test_conservatively_marked_block() {
-        let mut b = BumpBlock::new().unwrap();
+        let mut region = Region::default().unwrap();
+        let mut b = new_block(&mut region);
 
         for i in 0..LINE_COUNT {
             if i % 2 == 0 {
@@ -154,7 +228,8 @@ mod tests {
 
     #[test]
     fn test_current_hole_size() {
-        let block = BumpBlock::new().unwrap();
+        let mut region = Region::default().unwrap();
+        let block = new_block(&mut region);
         let expect = block.current_hole_size();
 
         assert_eq!(expect, BLOCK_CAPACITY);
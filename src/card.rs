@@ -0,0 +1,69 @@
+use super::block_meta::BlockMeta;
+use super::constants::{BLOCK_SIZE, CARD_SIZE};
+use super::header::Header;
+use std::ptr::NonNull;
+
+// A dirty card taken from the remembered set by Arena::dirty_cards. The write
+// barrier dirties the card holding the header of the object written to, so
+// the objects to scan again are the ones starting inside the card. A large
+// object is remembered as a card of its own.
+pub struct Card {
+    start: *const u8,
+    end: *const u8,
+    large: bool,
+}
+
+impl Card {
+    pub(crate) fn new(block: *const u8, card: usize) -> Self {
+        let start = unsafe { block.add(card * CARD_SIZE) };
+
+        Self {
+            start,
+            end: unsafe { start.add(CARD_SIZE) },
+            large: false,
+        }
+    }
+
+    pub(crate) fn large(header: *const Header) -> Self {
+        let size = unsafe { (*header).get_size() } as usize;
+        let start = header as *const u8;
+
+        Self {
+            start,
+            end: unsafe { start.add(size) },
+            large: true,
+        }
+    }
+
+    pub fn get_start(&self) -> *const u8 {
+        self.start
+    }
+
+    pub fn get_end(&self) -> *const u8 {
+        self.end
+    }
+
+    // Calls f with the start and header of every object starting in the card.
+    pub fn for_each_object<F>(&self, mut f: F)
+    where
+        F: FnMut(NonNull<u8>, &Header),
+    {
+        let mut visit = |header: *const Header| {
+            let object = Header::object_start(header) as *mut u8;
+
+            f(NonNull::new(object).unwrap(), unsafe { &*header });
+        };
+
+        if self.large {
+            visit(self.start as *const Header);
+            return;
+        }
+
+        let offset = self.start as usize % BLOCK_SIZE;
+        let block = unsafe { self.start.sub(offset) };
+
+        for start in BlockMeta::from_block(block).object_starts(offset..offset + CARD_SIZE) {
+            visit(unsafe { block.add(start) } as *const Header);
+        }
+    }
+}
@@ -1,14 +1,30 @@
 pub const BLOCK_SIZE: usize = 1024 * 32;
 pub const LINE_SIZE: usize = 128;
-pub const REGION_SIZE: usize = BLOCK_SIZE * 32;
+pub const REGION_BLOCKS: usize = 32;
+pub const REGION_SIZE: usize = BLOCK_SIZE * REGION_BLOCKS;
 
 // How many total lines are in a block, but one of these lines is actually just
 // for line mark bits. This is pretty confusing..probably desrves a rename.
 // Like RAW_LINES, and LINE_COUNT to make clear there is a distinction.
 pub const LINE_COUNT: usize = BLOCK_SIZE / LINE_SIZE;
 
-pub const BLOCK_CAPACITY: usize = BLOCK_SIZE - LINE_COUNT;
+// Every allocation starts on a granule, the object start bitmap has a bit
+// for each granule in the block.
+pub const OBJECT_GRANULE: usize = 8;
+pub const START_BITMAP_SIZE: usize = BLOCK_SIZE / OBJECT_GRANULE / 8;
+
+// The write barrier dirties one card byte per CARD_SIZE bytes of the block.
+pub const CARD_SIZE: usize = 512;
+pub const CARD_COUNT: usize = BLOCK_SIZE / CARD_SIZE;
+
+// The line marks, object start bitmap and card table, rounded up to whole lines.
+pub const BLOCK_META_SIZE: usize =
+    (LINE_COUNT + START_BITMAP_SIZE + CARD_COUNT).next_multiple_of(LINE_SIZE);
+
+pub const BLOCK_CAPACITY: usize = BLOCK_SIZE - BLOCK_META_SIZE;
 pub const LINE_MARK_START: usize = BLOCK_CAPACITY;
+pub const START_BITMAP_START: usize = LINE_MARK_START + LINE_COUNT;
+pub const CARD_TABLE_START: usize = START_BITMAP_START + START_BITMAP_SIZE;
 
 pub const MAX_ALLOC_SIZE: usize = u32::MAX as usize;
 pub const SMALL_OBJECT_MIN: usize = 1;
@@ -17,3 +33,16 @@ pub const MEDIUM_OBJECT_MIN: usize = SMALL_OBJECT_MAX + 1;
 pub const MEDIUM_OBJECT_MAX: usize = BLOCK_CAPACITY;
 pub const LARGE_OBJECT_MIN: usize = MEDIUM_OBJECT_MAX + 1;
 pub const LARGE_OBJECT_MAX: usize = MAX_ALLOC_SIZE;
+
+// A parallel tracer offers half of its mark stack to the other workers once
+// it grows past this many entries.
+pub const MARK_STACK_SHARE: usize = 64;
+
+// Blocks the background sweeper sweeps each time it takes the refresh lock.
+pub const BACKGROUND_SWEEP_BATCH: usize = 32;
+
+// Overwritten references an allocator logs before flushing them to the arena.
+pub const SATB_BUFFER_SIZE: usize = 256;
+
+// Recycled blocks with at most this many live lines get evacuated.
+pub const EVACUATE_MAX_LIVE_LINES: usize = BLOCK_CAPACITY / LINE_SIZE / 4;
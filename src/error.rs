@@ -0,0 +1,65 @@
+use super::constants::MAX_ALLOC_SIZE;
+use std::alloc::Layout;
+use std::error::Error;
+use std::fmt;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AllocErrorKind {
+    // the requested layout has a size of zero
+    ZeroSize,
+    // the requested layout, plus its header, is bigger than MAX_ALLOC_SIZE
+    TooLarge,
+    // no valid layout could be made for the object and its header
+    InvalidLayout,
+    // the system allocator couldn't provide a block
+    OutOfMemory,
+    // the arena's configured heap limit would be exceeded
+    HeapLimit,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AllocError {
+    kind: AllocErrorKind,
+    layout: Layout,
+}
+
+impl AllocError {
+    pub fn new(kind: AllocErrorKind, layout: Layout) -> Self {
+        Self { kind, layout }
+    }
+
+    pub fn kind(&self) -> AllocErrorKind {
+        self.kind
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+}
+
+impl fmt::Display for AllocError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let size = self.layout.size();
+        let align = self.layout.align();
+
+        match self.kind {
+            AllocErrorKind::ZeroSize => write!(f, "cannot allocate a zero sized object"),
+            AllocErrorKind::TooLarge => write!(
+                f,
+                "allocation of {size} bytes exceeds the maximum allocation size of {MAX_ALLOC_SIZE} bytes"
+            ),
+            AllocErrorKind::InvalidLayout => write!(
+                f,
+                "invalid layout for allocation of {size} bytes aligned to {align}"
+            ),
+            AllocErrorKind::OutOfMemory => {
+                write!(f, "out of memory while allocating {size} bytes")
+            }
+            AllocErrorKind::HeapLimit => {
+                write!(f, "heap limit reached while allocating {size} bytes")
+            }
+        }
+    }
+}
+
+impl Error for AllocError {}
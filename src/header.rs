@@ -1,7 +1,8 @@
 use super::allocate::Marker;
 use super::block_meta::BlockMeta;
 use super::size_class::SizeClass;
-use std::sync::atomic::{AtomicU8, Ordering};
+use std::mem::size_of;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
 
 #[repr(u8)]
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
@@ -33,6 +34,16 @@ impl Mark {
             _ => panic!("Attempted to rotate a mark that shouldn't be rotated"),
         }
     }
+
+    // Whether something with this mark is kept by a sweep with the given mark.
+    // Sweeping with Mark::New is a minor sweep, which keeps everything that
+    // has ever been marked and only frees what never was.
+    pub fn survives(&self, sweep: Mark) -> bool {
+        match sweep {
+            Mark::New => *self != Mark::New,
+            _ => *self == sweep,
+        }
+    }
 }
 
 impl From<u8> for Mark {
@@ -48,19 +59,43 @@ impl From<u8> for Mark {
     }
 }
 
+const PINNED_FLAG: u8 = 0b0000_0001;
+// set while a large object is in the remembered set
+const LOGGED_FLAG: u8 = 0b0000_0010;
+// set by the evacuation that claimed the object, only one of this and
+// PINNED_FLAG is ever set on an object that can move
+const FORWARDED_FLAG: u8 = 0b0000_0100;
+
 #[repr(C)]
 pub struct Header {
     mark: AtomicU8,
     size_class: SizeClass,
-    size: u16,
+    // log2 of the allocation's alignment, used to find the object from its header
+    align_shift: u8,
+    flags: AtomicU8,
+    size: u32,
+    // address of the evacuated copy of this object, 0 if it hasn't moved
+    forward: AtomicUsize,
 }
 
 impl Header {
-    pub fn new(size_class: SizeClass, size: u16) -> Self {
+    pub fn new(size_class: SizeClass, size: u32, align: usize) -> Self {
+        debug_assert!(align.is_power_of_two());
+
+        // large objects never move, so they start out pinned
+        let flags = if size_class == SizeClass::Large {
+            PINNED_FLAG
+        } else {
+            0
+        };
+
         Header {
             mark: AtomicU8::new(Mark::New as u8),
             size_class,
+            align_shift: align.trailing_zeros() as u8,
+            flags: AtomicU8::new(flags),
             size,
+            forward: AtomicUsize::new(0),
         }
     }
 
@@ -72,10 +107,127 @@ impl Header {
         self.size_class
     }
 
-    pub fn get_size(&self) -> u16 {
+    pub fn get_size(&self) -> u32 {
         self.size
     }
 
+    pub fn get_align(&self) -> usize {
+        1 << self.align_shift
+    }
+
+    // The object sits right after the header, padded out to its alignment.
+    pub fn object_start(this: *const Header) -> *const u8 {
+        let align = unsafe { (*this).get_align() };
+
+        unsafe { (this as *const u8).add(Self::object_offset(align)) }
+    }
+
+    pub fn object_offset(align: usize) -> usize {
+        let header_size = size_of::<Header>();
+        let padding = (align - (header_size % align)) % align;
+
+        header_size + padding
+    }
+
+    pub fn get_forward(this: *const Header) -> Option<*const u8> {
+        match unsafe { (*this).forward.load(Ordering::Acquire) } {
+            0 => None,
+            addr => Some(addr as *const u8),
+        }
+    }
+
+    pub fn is_pinned(&self) -> bool {
+        self.flags.load(Ordering::Acquire) & PINNED_FLAG != 0
+    }
+
+    // Returns whether the object was already pinned. An object that was
+    // already claimed by an evacuation can't be pinned any more, the address
+    // of its copy is returned instead.
+    pub fn pin(this: *const Header) -> Result<bool, *const u8> {
+        let flags = unsafe {
+            (*this)
+                .flags
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |flags| {
+                    (flags & FORWARDED_FLAG == 0).then_some(flags | PINNED_FLAG)
+                })
+        };
+
+        match flags {
+            Ok(flags) => Ok(flags & PINNED_FLAG != 0),
+            Err(_) => Err(Self::wait_forward(this)),
+        }
+    }
+
+    // Returns whether the object was pinned.
+    pub fn unpin(this: *const Header) -> bool {
+        unsafe { (*this).flags.fetch_and(!PINNED_FLAG, Ordering::AcqRel) & PINNED_FLAG != 0 }
+    }
+
+    // Returns whether the object was already logged.
+    pub fn set_logged(this: *const Header, logged: bool) -> bool {
+        let flags = unsafe {
+            if logged {
+                (*this).flags.fetch_or(LOGGED_FLAG, Ordering::AcqRel)
+            } else {
+                (*this).flags.fetch_and(!LOGGED_FLAG, Ordering::AcqRel)
+            }
+        };
+
+        flags & LOGGED_FLAG != 0
+    }
+
+    pub fn is_forwarded(this: *const Header) -> bool {
+        Self::get_forward(this).is_some()
+    }
+
+    // Only the first forward installed on a header sticks, if another thread
+    // already forwarded the object the winning address is returned instead.
+    // The object is claimed in its flags, so it is either pinned or forwarded
+    // and never both. A pinned object gives back None.
+    pub fn try_forward(this: *const Header, object: *const u8) -> Result<(), Option<*const u8>> {
+        debug_assert!(!object.is_null());
+
+        let claimed = unsafe {
+            (*this)
+                .flags
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |flags| {
+                    (flags & (PINNED_FLAG | FORWARDED_FLAG) == 0).then_some(flags | FORWARDED_FLAG)
+                })
+        };
+
+        match claimed {
+            Ok(_) => {
+                unsafe { (*this).forward.store(object as usize, Ordering::Release) };
+                Ok(())
+            }
+            Err(flags) if flags & PINNED_FLAG != 0 => Err(None),
+            Err(_) => Err(Some(Self::wait_forward(this))),
+        }
+    }
+
+    // The claim comes just before the address is stored, so this only spins
+    // for as long as that takes.
+    fn wait_forward(this: *const Header) -> *const u8 {
+        loop {
+            if let Some(forward) = Self::get_forward(this) {
+                return forward;
+            }
+
+            std::hint::spin_loop();
+        }
+    }
+
+    // A copy made for an evacuation starts out neither forwarded nor pinned,
+    // whatever happened to the original while it was being copied.
+    pub fn clear_copy(this: *const Header) {
+        unsafe {
+            (*this).forward.store(0, Ordering::Relaxed);
+            (*this)
+                .flags
+                .fetch_and(!(PINNED_FLAG | FORWARDED_FLAG), Ordering::Release);
+        }
+    }
+
     /*
     pub fn mark_new(&self) {
         self.mark.store(Mark::New as u8, Ordering::SeqCst)
@@ -85,7 +237,32 @@ impl Header {
     pub fn set_mark(this: *const Header, mark: Mark) {
         unsafe {
             (*this).mark.store(mark as u8, Ordering::Release);
+        }
+
+        Self::mark_lines(this, mark);
+    }
 
+    // Marks the object only if it isn't marked with mark already. Of several
+    // threads trying to mark the same object exactly one gets true back.
+    pub fn try_mark(this: *const Header, mark: Mark) -> bool {
+        let claimed = unsafe {
+            (*this)
+                .mark
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| {
+                    (current != mark as u8).then_some(mark as u8)
+                })
+                .is_ok()
+        };
+
+        if claimed {
+            Self::mark_lines(this, mark);
+        }
+
+        claimed
+    }
+
+    fn mark_lines(this: *const Header, mark: Mark) {
+        unsafe {
             if mark != Mark::New && (*this).size_class != SizeClass::Large {
                 let meta = BlockMeta::from_header(this);
 
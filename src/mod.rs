@@ -1,17 +1,45 @@
+// the baseline tests predate these lints
+#![cfg_attr(
+    test,
+    allow(
+        clippy::legacy_numeric_constants,
+        clippy::manual_is_multiple_of,
+        clippy::manual_while_let_some
+    )
+)]
+
 mod alloc_head;
 mod allocate;
 mod allocator;
 mod arena;
+mod arena_config;
 mod block;
+mod block_list;
 mod block_meta;
 mod block_store;
 mod bump_block;
+mod card;
 mod constants;
+mod error;
 mod header;
+mod refresh;
+mod region;
+mod reservation;
+mod satb;
 mod size_class;
+mod sweeper;
+mod trace;
+mod weak;
 
 #[cfg(test)]
 mod tests;
 
 pub use allocate::{Allocate, GenerationalArena, Marker};
 pub use allocator::Allocator;
+pub use arena::Arena;
+pub use arena_config::ArenaConfig;
+pub use card::Card;
+pub use error::{AllocError, AllocErrorKind};
+pub use refresh::{RefreshBudget, RefreshProgress};
+pub use trace::{Trace, Tracer};
+pub use weak::Weak;
@@ -0,0 +1,52 @@
+use std::time::{Duration, Instant};
+
+// How much work a single Arena::refresh_step may do. Running a finalizer
+// and releasing a region count the same as sweeping a block.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RefreshBudget {
+    // stop once this much time has passed, checked after every block, finalizer
+    // or region
+    Time(Duration),
+    // sweep at most this many blocks, large objects count as a block each
+    Blocks(usize),
+}
+
+impl RefreshBudget {
+    // At least one block is always swept, so repeated steps always finish.
+    pub(crate) fn is_spent(&self, start: Instant, swept: usize) -> bool {
+        if swept == 0 {
+            return false;
+        }
+
+        match self {
+            RefreshBudget::Time(budget) => start.elapsed() >= *budget,
+            RefreshBudget::Blocks(blocks) => swept >= *blocks,
+        }
+    }
+}
+
+impl From<Duration> for RefreshBudget {
+    fn from(budget: Duration) -> Self {
+        RefreshBudget::Time(budget)
+    }
+}
+
+impl From<usize> for RefreshBudget {
+    fn from(blocks: usize) -> Self {
+        RefreshBudget::Blocks(blocks)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum RefreshProgress {
+    // the number of finalizers still to run, or blocks and large objects still
+    // waiting to be swept, or regions still to be released
+    InProgress(usize),
+    Done,
+}
+
+impl RefreshProgress {
+    pub fn is_done(&self) -> bool {
+        *self == RefreshProgress::Done
+    }
+}
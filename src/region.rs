@@ -1,8 +1,8 @@
-use super::constants::REGION_SIZE;
+use super::constants::{BLOCK_SIZE, REGION_BLOCKS, REGION_SIZE};
+use super::error::AllocErrorKind;
 use std::alloc::{alloc, dealloc, Layout};
 use std::ptr::NonNull;
 
-
 // we need a new block!
 // if we have a free block in the store use that
 // else we need to allocate a new region
@@ -11,28 +11,87 @@ use std::ptr::NonNull;
 // Now we cant dealloc a block directly,
 // we must dealloc a whole region
 //
-// we only dealloc regions after defragmentation
-
+// we only dealloc regions once every block carved from them is free
+//
+// Regions are aligned to their own size, so the region a block belongs to
+// can always be found by rounding the block address down to REGION_SIZE.
 pub struct Region {
     ptr: NonNull<u8>,
-    layout: Layout,
-
+    // None when the memory belongs to a Reservation
+    layout: Option<Layout>,
+    carved: usize,
 }
 
+unsafe impl Send for Region {}
+unsafe impl Sync for Region {}
+
 impl Region {
-    pub fn default() -> Result<Region, ()> {
-        let layout = Layout::from_size_align(REGION_SIZE, BLOCK_SIZE).unwrap();
+    pub fn default() -> Result<Region, AllocErrorKind> {
+        let layout = Layout::from_size_align(REGION_SIZE, REGION_SIZE).unwrap();
 
         Self::new(layout)
     }
 
-    pub fn new(layout: Layout) -> Result<Region, ()> {
+    pub fn new(layout: Layout) -> Result<Region, AllocErrorKind> {
+        debug_assert!(layout.size().is_multiple_of(BLOCK_SIZE));
+        debug_assert!(layout.align() >= BLOCK_SIZE);
+
         Ok(Region {
             ptr: Self::alloc(layout)?,
-            layout,
+            layout: Some(layout),
+            carved: 0,
         })
     }
 
+    pub fn reserved(ptr: *mut u8) -> Region {
+        debug_assert!((ptr as usize).is_multiple_of(REGION_SIZE));
+
+        Region {
+            ptr: NonNull::new(ptr).unwrap(),
+            layout: None,
+            carved: 0,
+        }
+    }
+
+    pub fn base_of(ptr: *const u8) -> usize {
+        ptr as usize & !(REGION_SIZE - 1)
+    }
+
+    // Hands the pages back to the OS while keeping the address range, touching
+    // them again maps in fresh zeroed pages. Elsewhere this does nothing. The
+    // memory has to be mapped by us, see Reservation.
+    pub fn decommit(ptr: *const u8, len: usize) {
+        #[cfg(target_os = "linux")]
+        {
+            let result =
+                unsafe { libc::madvise(ptr as *mut libc::c_void, len, libc::MADV_DONTNEED) };
+
+            debug_assert_eq!(result, 0);
+        }
+
+        #[cfg(not(target_os = "linux"))]
+        let _ = (ptr, len);
+    }
+
+    pub fn carve_block(&mut self) -> Option<*const u8> {
+        if self.is_exhausted() {
+            return None;
+        }
+
+        let block = self.at_offset(self.carved * BLOCK_SIZE);
+        self.carved += 1;
+
+        Some(block)
+    }
+
+    pub fn carved(&self) -> usize {
+        self.carved
+    }
+
+    pub fn is_exhausted(&self) -> bool {
+        self.carved == REGION_BLOCKS
+    }
+
     pub fn at_offset(&self, offset: usize) -> *const u8 {
         debug_assert!(offset < REGION_SIZE);
 
@@ -43,16 +102,12 @@ impl Region {
         self.ptr.as_ptr()
     }
 
-    pub fn get_size(&self) -> usize {
-        self.layout.size()
-    }
-
-    fn alloc(layout: Layout) -> Result<NonNull<u8>, ()> {
+    fn alloc(layout: Layout) -> Result<NonNull<u8>, AllocErrorKind> {
         unsafe {
             let ptr = alloc(layout);
 
             if ptr.is_null() {
-                Err(())
+                Err(AllocErrorKind::OutOfMemory)
             } else {
                 Ok(NonNull::new_unchecked(ptr))
             }
@@ -62,6 +117,28 @@ impl Region {
 
 impl Drop for Region {
     fn drop(&mut self) {
-        unsafe { dealloc(self.ptr.as_ptr(), self.layout) }
+        if let Some(layout) = self.layout {
+            unsafe { dealloc(self.ptr.as_ptr(), layout) }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn carve_every_block() {
+        let mut region = Region::default().unwrap();
+
+        for i in 0..REGION_BLOCKS {
+            let block = region.carve_block().unwrap();
+
+            assert_eq!(block as usize, region.as_ptr() as usize + i * BLOCK_SIZE);
+            assert_eq!(Region::base_of(block), region.as_ptr() as usize);
+        }
+
+        assert!(region.is_exhausted());
+        assert!(region.carve_block().is_none());
     }
 }
@@ -0,0 +1,177 @@
+use super::constants::{BLOCK_SIZE, REGION_SIZE};
+use super::error::AllocErrorKind;
+use super::region::Region;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+// One contiguous range of address space mapped with no access rights.
+// Regions are committed from it on demand, so every region the arena uses
+// sits between base and base + len, and finding the region or block of a
+// pointer is a subtraction.
+pub struct Reservation {
+    map: *mut u8,
+    map_len: usize,
+    base: usize,
+    len: usize,
+    committed: Box<[AtomicBool]>,
+    // indexes of the regions that can be committed, lowest last
+    uncommitted: Mutex<Vec<usize>>,
+}
+
+unsafe impl Send for Reservation {}
+unsafe impl Sync for Reservation {}
+
+impl Reservation {
+    #[cfg(target_os = "linux")]
+    pub fn new(bytes: usize) -> Result<Reservation, AllocErrorKind> {
+        let regions = bytes.div_ceil(REGION_SIZE).max(1);
+        let len = regions
+            .checked_mul(REGION_SIZE)
+            .ok_or(AllocErrorKind::OutOfMemory)?;
+        // the extra region lets us align the base to REGION_SIZE
+        let map_len = len
+            .checked_add(REGION_SIZE)
+            .ok_or(AllocErrorKind::OutOfMemory)?;
+
+        let map = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                map_len,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+
+        if map == libc::MAP_FAILED {
+            return Err(AllocErrorKind::OutOfMemory);
+        }
+
+        Ok(Reservation {
+            map: map as *mut u8,
+            map_len,
+            base: (map as usize).next_multiple_of(REGION_SIZE),
+            len,
+            committed: (0..regions).map(|_| AtomicBool::new(false)).collect(),
+            uncommitted: Mutex::new((0..regions).rev().collect()),
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn new(_bytes: usize) -> Result<Reservation, AllocErrorKind> {
+        Err(AllocErrorKind::OutOfMemory)
+    }
+
+    pub fn commit_region(&self) -> Result<Region, AllocErrorKind> {
+        let mut uncommitted = self.uncommitted.lock().unwrap();
+        let index = *uncommitted.last().ok_or(AllocErrorKind::OutOfMemory)?;
+        let ptr = (self.base + index * REGION_SIZE) as *mut u8;
+
+        if !Self::protect(ptr, true) {
+            return Err(AllocErrorKind::OutOfMemory);
+        }
+
+        uncommitted.pop();
+        self.committed[index].store(true, Ordering::Release);
+
+        Ok(Region::reserved(ptr))
+    }
+
+    // Gives the region's pages back to the OS, the address range stays reserved.
+    pub fn release_region(&self, region: &Region) {
+        let ptr = region.as_ptr();
+        let index = self.region_index(ptr).unwrap();
+
+        self.committed[index].store(false, Ordering::Release);
+        Region::decommit(ptr, REGION_SIZE);
+        Self::protect(ptr as *mut u8, false);
+
+        self.uncommitted.lock().unwrap().push(index);
+    }
+
+    pub fn contains(&self, ptr: *const u8) -> bool {
+        self.region_index(ptr)
+            .is_some_and(|index| self.committed[index].load(Ordering::Acquire))
+    }
+
+    pub fn block_index(&self, ptr: *const u8) -> Option<usize> {
+        if !self.contains(ptr) {
+            return None;
+        }
+
+        Some((ptr as usize - self.base) / BLOCK_SIZE)
+    }
+
+    fn region_index(&self, ptr: *const u8) -> Option<usize> {
+        let offset = (ptr as usize).checked_sub(self.base)?;
+
+        if offset < self.len {
+            Some(offset / REGION_SIZE)
+        } else {
+            None
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn protect(ptr: *mut u8, writable: bool) -> bool {
+        let prot = if writable {
+            libc::PROT_READ | libc::PROT_WRITE
+        } else {
+            libc::PROT_NONE
+        };
+
+        unsafe { libc::mprotect(ptr as *mut libc::c_void, REGION_SIZE, prot) == 0 }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn protect(_ptr: *mut u8, _writable: bool) -> bool {
+        false
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        #[cfg(target_os = "linux")]
+        unsafe {
+            libc::munmap(self.map as *mut libc::c_void, self.map_len);
+        }
+    }
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::super::constants::REGION_BLOCKS;
+    use super::*;
+
+    #[test]
+    fn commit_and_release() {
+        let reservation = Reservation::new(REGION_SIZE * 2).unwrap();
+        let mut first = reservation.commit_region().unwrap();
+        let second = reservation.commit_region().unwrap();
+
+        assert!((first.as_ptr() as usize).is_multiple_of(REGION_SIZE));
+        assert_eq!(
+            second.as_ptr() as usize,
+            first.as_ptr() as usize + REGION_SIZE
+        );
+        assert!(reservation.commit_region().is_err());
+
+        let block = first.carve_block().unwrap();
+        unsafe { *(block as *mut u8) = 1 };
+
+        assert!(reservation.contains(block));
+        assert_eq!(reservation.block_index(block), Some(0));
+        assert_eq!(
+            reservation.block_index(second.as_ptr()),
+            Some(REGION_BLOCKS)
+        );
+
+        reservation.release_region(&second);
+        assert!(!reservation.contains(second.as_ptr()));
+        assert!(!reservation.contains(std::ptr::null()));
+
+        let third = reservation.commit_region().unwrap();
+        assert_eq!(third.as_ptr(), second.as_ptr());
+    }
+}
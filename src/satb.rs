@@ -0,0 +1,46 @@
+use super::block_store::BlockStore;
+use super::constants::SATB_BUFFER_SIZE;
+use std::sync::{Arc, Mutex};
+
+// An allocator's log of references overwritten while the arena is marking.
+// Full buffers are flushed to the arena's queue, and the buffer is registered
+// with the BlockStore so a marker can drain what is left without waiting on
+// the allocator.
+pub struct SatbBuffer {
+    entries: Arc<Mutex<Vec<usize>>>,
+    block_store: Arc<BlockStore>,
+}
+
+impl SatbBuffer {
+    pub fn new(block_store: Arc<BlockStore>) -> Self {
+        let entries = Arc::new(Mutex::new(Vec::with_capacity(SATB_BUFFER_SIZE)));
+
+        block_store.register_satb_buffer(&entries);
+
+        Self {
+            entries,
+            block_store,
+        }
+    }
+
+    pub fn push(&self, object: *const u8) {
+        let mut entries = self.entries.lock().unwrap();
+
+        entries.push(object as usize);
+
+        if entries.len() >= SATB_BUFFER_SIZE {
+            self.block_store.push_satb(&mut entries);
+        }
+    }
+
+    pub fn flush(&self) {
+        self.block_store
+            .push_satb(&mut self.entries.lock().unwrap());
+    }
+}
+
+impl Drop for SatbBuffer {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
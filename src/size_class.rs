@@ -1,4 +1,5 @@
 use super::constants;
+use super::error::AllocErrorKind;
 
 #[repr(u8)]
 #[derive(Debug, Copy, Clone, PartialEq)]
@@ -9,12 +10,13 @@ pub enum SizeClass {
 }
 
 impl SizeClass {
-    pub fn get_for_size(object_size: usize) -> Result<SizeClass, ()> {
+    pub fn get_for_size(object_size: usize) -> Result<SizeClass, AllocErrorKind> {
         match object_size {
             constants::SMALL_OBJECT_MIN..=constants::SMALL_OBJECT_MAX => Ok(SizeClass::Small),
             constants::MEDIUM_OBJECT_MIN..=constants::MEDIUM_OBJECT_MAX => Ok(SizeClass::Medium),
             constants::LARGE_OBJECT_MIN..=constants::LARGE_OBJECT_MAX => Ok(SizeClass::Large),
-            _ => Err(()),
+            0 => Err(AllocErrorKind::ZeroSize),
+            _ => Err(AllocErrorKind::TooLarge),
         }
     }
 }
@@ -0,0 +1,126 @@
+use super::block_store::BlockStore;
+use super::constants::BACKGROUND_SWEEP_BATCH;
+use super::refresh::RefreshBudget;
+use std::sync::{Arc, Condvar, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+
+#[derive(Default)]
+struct SweepState {
+    pending: bool,
+    shutdown: bool,
+}
+
+// Shared between the store, which signals after every refresh, and the
+// sweeper thread waiting on it.
+#[derive(Default)]
+pub struct SweepSignal {
+    state: Mutex<SweepState>,
+    condvar: Condvar,
+}
+
+impl SweepSignal {
+    pub fn notify(&self) {
+        self.state.lock().unwrap().pending = true;
+        self.condvar.notify_one();
+    }
+
+    // Blocks until there is sweeping to do, false once shut down.
+    fn wait(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        while !state.pending && !state.shutdown {
+            state = self.condvar.wait(state).unwrap();
+        }
+
+        state.pending = false;
+        !state.shutdown
+    }
+
+    fn shutdown(&self) {
+        self.state.lock().unwrap().shutdown = true;
+        self.condvar.notify_one();
+    }
+}
+
+// The arena owned thread that sweeps blocks queued up by a refresh. It only
+// holds the store weakly, and is shut down and joined when the last handle to
+// the arena is dropped.
+pub struct Sweeper {
+    signal: Arc<SweepSignal>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Sweeper {
+    pub fn spawn(store: Weak<BlockStore>, signal: Arc<SweepSignal>) -> Self {
+        let thread_signal = signal.clone();
+        let thread = thread::spawn(move || {
+            while thread_signal.wait() {
+                let Some(store) = store.upgrade() else {
+                    return;
+                };
+
+                // the refresh lock is let go between batches, so allocators
+                // and refreshes are never held up for long
+                while !store
+                    .sweep_queued(RefreshBudget::Blocks(BACKGROUND_SWEEP_BATCH))
+                    .is_done()
+                {}
+            }
+        });
+
+        Self {
+            signal,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Sweeper {
+    fn drop(&mut self) {
+        self.signal.shutdown();
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::allocate::{Allocate, GenerationalArena};
+    use super::super::allocator::Allocator;
+    use super::super::arena::Arena;
+    use super::super::arena_config::ArenaConfig;
+    use super::super::constants::BLOCK_SIZE;
+    use std::alloc::Layout;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn sweep_in_the_background() {
+        let arena = Arena::new_with_config(ArenaConfig::new().background_sweep(true));
+        let allocator = Allocator::new(&arena);
+        let layout = Layout::new::<[u8; 64]>();
+
+        for _ in 0..(BLOCK_SIZE * 8 / 64) {
+            allocator.alloc(layout).unwrap();
+        }
+
+        let size = arena.get_size();
+        arena.refresh();
+
+        let start = Instant::now();
+        while arena.is_sweeping() {
+            assert!(start.elapsed() < Duration::from_secs(10));
+            std::thread::yield_now();
+        }
+
+        assert!(arena.get_size() < size);
+
+        // allocating after the sweep picks up the swept blocks
+        allocator.alloc(layout).unwrap();
+
+        // dropping the last handle to the arena joins the thread
+        drop(allocator);
+        drop(arena);
+    }
+}
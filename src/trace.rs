@@ -0,0 +1,360 @@
+use super::allocator::Allocator;
+use super::constants::MARK_STACK_SHARE;
+use super::header::Mark;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+// Implemented by anything holding references to objects in the arena, trace
+// should hand every such reference to Tracer::mark.
+pub trait Trace {
+    fn trace(&self, tracer: &mut Tracer);
+}
+
+type TraceFn = unsafe fn(NonNull<u8>, &mut Tracer);
+
+struct Work(NonNull<u8>, TraceFn);
+
+// objects are only ever traced by the worker that claimed them, and the
+// contract of Arena::collect_parallel makes that safe from any thread
+unsafe impl Send for Work {}
+
+// The work each parallel tracer has offered up to be stolen, and the number
+// of workers that still have work.
+struct WorkPool {
+    shared: Vec<Mutex<Vec<Work>>>,
+    active: AtomicUsize,
+}
+
+// Marks objects with a single mark. Objects are claimed as soon as they are
+// found, then traced later off of the mark stack, so cycles are only
+// followed once.
+pub struct Tracer {
+    mark: Mark,
+    stack: Vec<Work>,
+    // set when tracing in parallel, along with this worker's index in the pool
+    pool: Option<Arc<WorkPool>>,
+    id: usize,
+}
+
+impl Tracer {
+    pub(crate) fn new(mark: Mark) -> Self {
+        Self {
+            mark,
+            stack: vec![],
+            pool: None,
+            id: 0,
+        }
+    }
+
+    pub fn get_mark(&self) -> Mark {
+        self.mark
+    }
+
+    pub fn mark<T: Trace>(&mut self, ptr: NonNull<T>) {
+        if !Allocator::try_mark(ptr, self.mark) {
+            return;
+        }
+
+        self.stack.push(Work(ptr.cast(), trace_erased::<T>));
+
+        if self.stack.len() > MARK_STACK_SHARE {
+            self.share();
+        }
+    }
+
+    pub(crate) fn drain(&mut self) {
+        while let Some(Work(ptr, trace)) = self.stack.pop() {
+            unsafe { trace(ptr, self) };
+        }
+    }
+
+    // Traces everything the tracer has found so far on the given number of
+    // threads, the calling thread included. Workers steal from each other
+    // until all of them run out of work.
+    pub(crate) fn drain_parallel(mut self, workers: usize) {
+        let workers = workers.max(1);
+        let pool = Arc::new(WorkPool {
+            shared: (0..workers).map(|_| Mutex::new(vec![])).collect(),
+            active: AtomicUsize::new(workers),
+        });
+        let mark = self.mark;
+
+        self.pool = Some(pool.clone());
+        self.share();
+
+        thread::scope(|scope| {
+            for id in 1..workers {
+                let pool = pool.clone();
+
+                scope.spawn(move || {
+                    let tracer = Tracer {
+                        mark,
+                        stack: vec![],
+                        pool: Some(pool),
+                        id,
+                    };
+
+                    tracer.work();
+                });
+            }
+
+            self.work();
+        });
+    }
+
+    fn work(mut self) {
+        let pool = self.pool.clone().unwrap();
+
+        loop {
+            self.drain();
+
+            if self.steal(&pool) {
+                continue;
+            }
+
+            // Idle workers hold no work, so once none are active every
+            // shared stack is empty and marking is done.
+            pool.active.fetch_sub(1, Ordering::AcqRel);
+
+            loop {
+                if pool.active.load(Ordering::Acquire) == 0 {
+                    return;
+                }
+
+                pool.active.fetch_add(1, Ordering::AcqRel);
+
+                if self.steal(&pool) {
+                    break;
+                }
+
+                pool.active.fetch_sub(1, Ordering::AcqRel);
+                thread::yield_now();
+            }
+        }
+    }
+
+    // Offers half of the mark stack to the other workers, unless what was
+    // offered before hasn't been taken yet.
+    fn share(&mut self) {
+        let Some(pool) = &self.pool else {
+            return;
+        };
+
+        let mut shared = pool.shared[self.id].lock().unwrap();
+
+        if shared.is_empty() {
+            let half = self.stack.len() / 2;
+
+            shared.extend(self.stack.drain(..half));
+        }
+    }
+
+    // Takes back our own shared work first, then half of another worker's.
+    fn steal(&mut self, pool: &WorkPool) -> bool {
+        let workers = pool.shared.len();
+
+        for i in 0..workers {
+            let victim = (self.id + i) % workers;
+            let mut shared = pool.shared[victim].lock().unwrap();
+
+            if shared.is_empty() {
+                continue;
+            }
+
+            let take = if victim == self.id {
+                shared.len()
+            } else {
+                shared.len().div_ceil(2)
+            };
+
+            self.stack.extend(shared.drain(..take));
+            return true;
+        }
+
+        false
+    }
+}
+
+unsafe fn trace_erased<T: Trace>(ptr: NonNull<u8>, tracer: &mut Tracer) {
+    unsafe { ptr.cast::<T>().as_ref().trace(tracer) }
+}
+
+impl<T: Trace> Trace for NonNull<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        tracer.mark(*self);
+    }
+}
+
+impl<T: Trace> Trace for Option<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        if let Some(value) = self {
+            value.trace(tracer);
+        }
+    }
+}
+
+impl<T: Trace> Trace for Vec<T> {
+    fn trace(&self, tracer: &mut Tracer) {
+        for value in self.iter() {
+            value.trace(tracer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::allocate::{Allocate, GenerationalArena};
+    use super::super::arena::Arena;
+    use super::*;
+    use std::alloc::Layout;
+
+    struct Node {
+        value: usize,
+        next: Option<NonNull<Node>>,
+    }
+
+    impl Trace for Node {
+        fn trace(&self, tracer: &mut Tracer) {
+            self.next.trace(tracer);
+        }
+    }
+
+    fn alloc_node(
+        allocator: &Allocator,
+        value: usize,
+        next: Option<NonNull<Node>>,
+    ) -> NonNull<Node> {
+        let ptr: NonNull<Node> = allocator.alloc(Layout::new::<Node>()).unwrap().cast();
+
+        unsafe { ptr.as_ptr().write(Node { value, next }) };
+        ptr
+    }
+
+    #[test]
+    fn collect_reachable_objects() {
+        let arena = Arena::new();
+        let allocator = Allocator::new(&arena);
+        let mut list = None;
+        let mut garbage = vec![];
+
+        for i in 0..1_000 {
+            list = Some(alloc_node(&allocator, i, list));
+        }
+
+        for i in 0..1_000 {
+            garbage.push(arena.new_weak(alloc_node(&allocator, i, None)));
+        }
+
+        // close the list into a cycle
+        let head = list.unwrap();
+        let mut tail = head;
+        while let Some(next) = unsafe { tail.as_ref().next } {
+            tail = next;
+        }
+        unsafe { (*tail.as_ptr()).next = Some(head) };
+
+        // the allocator hands its blocks back so that they're swept too
+        drop(allocator);
+
+        let size = arena.get_size();
+        arena.collect(&[&list]);
+
+        assert!(arena.get_size() < size);
+        assert!(garbage.iter().all(|weak| weak.get().is_none()));
+
+        let mut node = head;
+        for i in (0..1_000).rev() {
+            assert_eq!(Allocator::get_mark(node), arena.current_mark());
+            assert_eq!(unsafe { node.as_ref().value }, i);
+            node = unsafe { node.as_ref().next.unwrap() };
+        }
+
+        assert_eq!(node, head);
+
+        arena.collect(&[]);
+        assert_eq!(arena.get_size(), 0);
+    }
+
+    struct Tree {
+        left: Option<NonNull<Tree>>,
+        right: Option<NonNull<Tree>>,
+    }
+
+    impl Trace for Tree {
+        fn trace(&self, tracer: &mut Tracer) {
+            self.left.trace(tracer);
+            self.right.trace(tracer);
+        }
+    }
+
+    fn alloc_tree(
+        allocator: &Allocator,
+        depth: usize,
+        nodes: &mut Vec<NonNull<Tree>>,
+    ) -> NonNull<Tree> {
+        let (left, right) = if depth == 0 {
+            (None, None)
+        } else {
+            (
+                Some(alloc_tree(allocator, depth - 1, nodes)),
+                Some(alloc_tree(allocator, depth - 1, nodes)),
+            )
+        };
+        let ptr: NonNull<Tree> = allocator.alloc(Layout::new::<Tree>()).unwrap().cast();
+
+        unsafe { ptr.as_ptr().write(Tree { left, right }) };
+        nodes.push(ptr);
+        ptr
+    }
+
+    #[test]
+    fn collect_in_parallel() {
+        let arena = Arena::new();
+        let allocator = Allocator::new(&arena);
+        let mut nodes = vec![];
+        let mut garbage = vec![];
+        let root = alloc_tree(&allocator, 14, &mut nodes);
+
+        // share the bottom of the tree between two parents
+        unsafe { (*nodes[0].as_ptr()).left = Some(nodes[nodes.len() / 2]) };
+        alloc_tree(&allocator, 10, &mut garbage);
+
+        let root = Some(root);
+        // Tree only holds pointers to other trees, which trace fine from any thread
+        unsafe { arena.collect_parallel(&[&root], 4) };
+
+        let mark = arena.current_mark();
+        assert!(nodes.iter().all(|node| Allocator::get_mark(*node) == mark));
+        assert!(garbage
+            .iter()
+            .all(|node| Allocator::get_mark(*node) != mark));
+    }
+
+    #[test]
+    fn one_marker_wins() {
+        let arena = Arena::new();
+        let allocator = Allocator::new(&arena);
+        let ptr = allocator.alloc(Layout::new::<u64>()).unwrap();
+        let addr = ptr.as_ptr() as usize;
+
+        let winners: usize = thread::scope(|scope| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| {
+                    scope.spawn(move || {
+                        let ptr = NonNull::new(addr as *mut u8).unwrap();
+
+                        Allocator::try_mark(ptr, Mark::Red) as usize
+                    })
+                })
+                .collect();
+
+            handles.into_iter().map(|h| h.join().unwrap()).sum()
+        });
+
+        assert_eq!(winners, 1);
+        assert!(!Allocator::try_mark(ptr, Mark::Red));
+        assert!(Allocator::try_mark(ptr, Mark::Green));
+    }
+}
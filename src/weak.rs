@@ -0,0 +1,37 @@
+use super::allocate::Allocate;
+use super::allocator::Allocator;
+use std::marker::PhantomData;
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// A handle to an object that doesn't keep it alive. The slot is shared with
+// the arena's weak table, a refresh clears it once the object is found
+// unmarked and moves it along when the object has been evacuated.
+pub struct Weak<T> {
+    slot: Arc<AtomicUsize>,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Weak<T> {
+    pub(crate) fn new(slot: Arc<AtomicUsize>) -> Self {
+        Self {
+            slot,
+            _marker: PhantomData,
+        }
+    }
+
+    // An object evacuated since the last refresh is followed to its copy, the
+    // slot itself is only moved along by the next refresh.
+    pub fn get(&self) -> Option<NonNull<T>> {
+        let ptr = NonNull::new(self.slot.load(Ordering::Acquire) as *mut T)?;
+
+        Some(Allocator::get_forward(ptr).unwrap_or(ptr))
+    }
+}
+
+impl<T> Clone for Weak<T> {
+    fn clone(&self) -> Self {
+        Self::new(self.slot.clone())
+    }
+}